@@ -0,0 +1,44 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/*
+* Embed the pattern library in src/patterns into the binary as the default library.
+* Writes EMBEDDED_PATTERNS, a list of (path inside src/patterns, file contents).
+*/
+fn main() {
+	println!("cargo:rerun-if-changed=src/patterns");
+	let library_root = Path::new(&env::var("CARGO_MANIFEST_DIR").unwrap()).join("src/patterns");
+	let mut files = Vec::new();
+	collect_json_files(&library_root, &mut files);
+	files.sort();
+
+	let mut embedded = String::from("pub const EMBEDDED_PATTERNS: &[(&str, &str)] = &[\n");
+	for file in files {
+		let relative_path = file.strip_prefix(&library_root).unwrap();
+		let relative_path: Vec<String> = relative_path
+			.components()
+			.map(|component| component.as_os_str().to_string_lossy().to_string())
+			.collect();
+		embedded.push_str(&format!(
+			"\t({:?}, include_str!({:?})),\n",
+			relative_path.join("/"),
+			file.display().to_string()
+		));
+	}
+	embedded.push_str("];\n");
+
+	let out_path = Path::new(&env::var("OUT_DIR").unwrap()).join("embedded_patterns.rs");
+	fs::write(out_path, embedded).expect("Could not write embedded pattern library");
+}
+
+fn collect_json_files(folder: &Path, files: &mut Vec<PathBuf>) {
+	for entry in fs::read_dir(folder).expect("Could not read pattern folder") {
+		let path = entry.expect("Could not read pattern folder entry").path();
+		if path.is_dir() {
+			collect_json_files(&path, files);
+		} else if path.extension().is_some_and(|extension| extension == "json") {
+			files.push(path);
+		}
+	}
+}
@@ -42,6 +42,20 @@ peak_times = librosa.frames_to_time(peaks, sr=sr)
 
 pitches, magnitudes = librosa.core.piptrack(y=y, sr=sr, fmin=100, fmax=1600, hop_length=512)
 
+# Chroma (how strong each of the 12 pitch classes is) at every peak, the generator uses it to find phrases that repeat
+chroma = librosa.feature.chroma_stft(y=y, sr=sr, hop_length=512)
+
+# Energy (RMS) at every peak, with the onset strength it tells where the song builds up and drops
+rms = librosa.feature.rms(y=y, hop_length=512)[0]
+
+def detect_energy(frame):
+  frame = min(frame, len(rms) - 1)
+  return "%.4f %.4f" % (onset_env[min(frame, len(onset_env) - 1)], rms[frame])
+
+def detect_chroma(frame):
+  frame = min(frame, chroma.shape[1] - 1)
+  return " ".join("%.4f" % value for value in chroma[:, frame])
+
 def detect_pitch(y, sr, t):
   index = magnitudes[:, int(t)].argmax()
   pitch = pitches[index, int(t)]
@@ -49,9 +63,9 @@ def detect_pitch(y, sr, t):
   return index, pitch
 
 text_file = open("src/song/peak_times.txt", "w")
-for peak_time in peak_times:
+for peak, peak_time in zip(peaks, peak_times):
 	index, pitch = detect_pitch(y, sr, peak_time)
-	text_file.write("%s %s %s\r\n" % (peak_time, index, pitch))
+	text_file.write("%s %s %s %s %s\r\n" % (peak_time, index, pitch, detect_energy(peak), detect_chroma(peak)))
 text_file.close()
 
 # Complete message
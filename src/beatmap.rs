@@ -0,0 +1,43 @@
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+/*
+* The parts of a difficulty .dat file we care about when reading a finished map back in
+*/
+#[derive(Serialize, Deserialize)]
+#[allow(non_snake_case)] //they're not snake case in the json
+pub struct DifficultyFile {
+	pub _notes: Vec<MapNote>,
+	pub _obstacles: Vec<MapObstacle>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[allow(non_snake_case)]
+pub struct MapNote {
+	pub _time: f64,
+	pub _lineIndex: i64,
+	pub _lineLayer: i64,
+	pub _type: i64,
+	pub _cutDirection: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+#[allow(non_snake_case)]
+pub struct MapObstacle {
+	pub _time: f64,
+	pub _lineIndex: i64,
+	pub _type: i64,
+	pub _duration: f64,
+	pub _width: f64,
+}
+
+pub fn read_difficulty_file(path: &Path) -> DifficultyFile {
+	let mut difficulty_file = File::open(path).expect("Could not open difficulty file");
+	let mut difficulty_json = String::new();
+	difficulty_file
+		.read_to_string(&mut difficulty_json)
+		.expect("Could not read difficulty file");
+	serde_json::from_str(&difficulty_json[..]).expect("Could not parse difficulty json")
+}
@@ -0,0 +1,160 @@
+use crate::flow::note_order;
+use crate::PatternNote;
+use rand::rngs::StdRng;
+use rand::Rng;
+
+pub const NOTE_TYPE_BOMB: i64 = 3;
+//chance out of 100 that a dynamic pattern gets a line of bombs across one layer
+const CHANCE_FOR_BOMB_LINE: i64 = 15;
+//chance out of 100 that a dynamic pattern gets a few bombs in empty cells
+const CHANCE_FOR_DECORATIVE_BOMBS: i64 = 25;
+const MAX_DECORATIVE_BOMBS: usize = 3;
+//how far either side of a bomb (in beats) a swing can still run into it
+const BOMB_SAFETY_WINDOW: f64 = 1.0;
+
+//x and y change for each cut direction, indexed by cut direction
+const CUT_VECTORS: [(i64, i64); 9] = [
+	(0, 1),
+	(0, -1),
+	(-1, 0),
+	(1, 0),
+	(-1, 1),
+	(1, 1),
+	(-1, -1),
+	(1, -1),
+	(0, 0),
+];
+
+fn cut_vector(cut_direction: i64) -> (i64, i64) {
+	CUT_VECTORS
+		.get(cut_direction as usize)
+		.cloned()
+		.unwrap_or((0, 0))
+}
+
+/*
+* The cells a saber passes through to cut a note: where it winds up from,
+* the note itself and where it follows through to
+*/
+pub fn swing_path_cells(note: &PatternNote) -> Vec<(i64, i64)> {
+	let (x_dir, y_dir) = cut_vector(note.cut_direction);
+	vec![
+		(note.x - x_dir, note.y - y_dir),
+		(note.x, note.y),
+		(note.x + x_dir, note.y + y_dir),
+	]
+}
+
+/*
+* The cells a saber crosses moving from one note to the next
+*/
+pub fn travel_cells(from: &PatternNote, to: &PatternNote) -> Vec<(i64, i64)> {
+	let steps = (to.x - from.x).abs().max((to.y - from.y).abs());
+	(0..=steps)
+		.map(|step| {
+			if steps == 0 {
+				return (from.x, from.y);
+			}
+			let x = from.x as f64 + (to.x - from.x) as f64 * step as f64 / steps as f64;
+			let y = from.y as f64 + (to.y - from.y) as f64 * step as f64 / steps as f64;
+			(x.round() as i64, y.round() as i64)
+		})
+		.collect()
+}
+
+//notes of a generated map with their time, which is the beat_time they carry from the start of the map
+pub fn map_note_times(notes: &[PatternNote]) -> Vec<(f64, &PatternNote)> {
+	notes.iter().map(|note| (note_order(note), note)).collect()
+}
+
+/*
+* A bomb is safe if no saber swings through its cell, or travels through it between two notes,
+* within BOMB_SAFETY_WINDOW of the bomb. The bomb's and the notes' times are in beats.
+*/
+pub fn bomb_is_safe(bomb: &PatternNote, bomb_time: f64, notes: &[(f64, &PatternNote)]) -> bool {
+	let bomb_cell = (bomb.x, bomb.y);
+	for hand in 0..2 {
+		let mut hand_notes: Vec<(f64, &PatternNote)> = notes
+			.iter()
+			.filter(|(_, note)| note.note_type == hand)
+			.cloned()
+			.collect();
+		hand_notes.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+
+		for (note_id, (note_time, note)) in hand_notes.iter().enumerate() {
+			if (note_time - bomb_time).abs() > BOMB_SAFETY_WINDOW {
+				continue;
+			}
+			if swing_path_cells(note).contains(&bomb_cell) {
+				return false;
+			}
+			if let Some((next_time, next_note)) = hand_notes.get(note_id + 1) {
+				if *note_time <= bomb_time
+					&& bomb_time <= *next_time
+					&& travel_cells(note, next_note).contains(&bomb_cell)
+				{
+					return false;
+				}
+			}
+		}
+	}
+	true
+}
+
+fn bomb(x: i64, y: i64, beat_time_rel: usize, description: &str) -> PatternNote {
+	PatternNote {
+		x,
+		y,
+		cut_direction: 0,
+		note_type: NOTE_TYPE_BOMB,
+		description: description.to_owned(),
+		beat_time_rel,
+		beat_time: None,
+	}
+}
+
+/*
+* Sometimes add a bomb line or a few decorative bombs to a dynamic pattern.
+* Only bombs that stay clear of every swing are kept, step_times is when (in beats) each step of the pattern is.
+*/
+pub fn add_dynamic_bombs(rng: &mut StdRng, notes: &mut Vec<PatternNote>, step_times: &[f64]) {
+	let last_step = match notes.iter().map(|note| note.beat_time_rel).max() {
+		Some(last_step) => last_step,
+		None => return,
+	};
+	let mut bombs = Vec::new();
+
+	if rng.gen_range(0, 100) < CHANCE_FOR_BOMB_LINE {
+		//run the line along whichever of the top or bottom layer the notes at that step leave free
+		let step = rng.gen_range(0, last_step + 1);
+		let top_is_free = !notes
+			.iter()
+			.any(|note| note.beat_time_rel == step && note.y == 2);
+		let layer = if top_is_free { 2 } else { 0 };
+		for x in 0..4 {
+			bombs.push(bomb(x, layer, step, "Bomb line"));
+		}
+	}
+	if rng.gen_range(0, 100) < CHANCE_FOR_DECORATIVE_BOMBS {
+		let number_of_bombs = rng.gen_range(1, MAX_DECORATIVE_BOMBS + 1);
+		for _ in 0..number_of_bombs {
+			let step = rng.gen_range(0, last_step + 1);
+			let x = rng.gen_range(0, 4);
+			let y = rng.gen_range(0, 3);
+			bombs.push(bomb(x, y, step, "Bomb"));
+		}
+	}
+
+	let step_time = |step: usize| step_times.get(step).or_else(|| step_times.last()).cloned().unwrap_or(0.0);
+	for bomb in bombs {
+		let already_there = notes.iter().any(|note| {
+			note.beat_time_rel == bomb.beat_time_rel && note.x == bomb.x && note.y == bomb.y
+		});
+		let timed_notes: Vec<(f64, &PatternNote)> =
+			notes.iter().map(|note| (step_time(note.beat_time_rel), note)).collect();
+		if !already_there && bomb_is_safe(&bomb, step_time(bomb.beat_time_rel), &timed_notes) {
+			notes.push(bomb);
+		}
+	}
+	notes.sort_by_key(|note| note.beat_time_rel);
+}
@@ -0,0 +1,276 @@
+use crate::bombs::NOTE_TYPE_BOMB;
+use crate::flow::note_order;
+use crate::settings::{DensitySettings, Difficulty};
+use crate::{Beat, Pattern, PatternNote};
+use serde::Serialize;
+
+/*
+* The notes per second each section of the map should have, sections are section_beats long from the start
+*/
+pub struct DensityPlan {
+	pub section_beats: f64,
+	pub targets: Vec<f64>,
+	pub tolerance: f64,
+}
+
+/*
+* What the map should do to get back to the target at a peak:
+* leave the peak empty, pick shorter patterns, carry on as it is, or pick longer patterns and keep quiet peaks
+*/
+#[derive(Clone, Copy, PartialEq)]
+pub enum DensityAdjust {
+	Drop,
+	Fewer,
+	AsIs,
+	More,
+}
+
+#[derive(Clone, Serialize)]
+pub struct SectionDensity {
+	pub start_beat: f64,
+	pub target_nps: f64,
+	pub achieved_nps: f64,
+}
+
+/*
+* Give every section the difficulty's target scaled by how intense its peaks are, from quiet_scale for the
+* calmest section to intense_scale for the most intense, evened out so the sections average the target.
+* No plan for a difficulty without a target.
+*/
+pub fn density_plan(
+	beats: &[Beat],
+	intensities: &[f64],
+	settings: &DensitySettings,
+	difficulty: Difficulty,
+	beats_per_minute: f64,
+) -> Option<DensityPlan> {
+	let target_nps = *settings.target_nps.get(&difficulty)?;
+	if settings.section_beats <= 0.0 || beats.is_empty() {
+		return None;
+	}
+	let section_of = |beat: &Beat| (((beat.peak_time_sec / 60.0) * beats_per_minute) / settings.section_beats) as usize;
+	let section_count = beats.iter().map(section_of).max().unwrap_or(0) + 1;
+
+	let mut totals = vec![(0.0, 0); section_count];
+	for (beat, intensity) in beats.iter().zip(intensities.iter()) {
+		let section = &mut totals[section_of(beat)];
+		section.0 += intensity;
+		section.1 += 1;
+	}
+	//a section without peaks counts as the calmest
+	let section_intensities: Vec<f64> = totals
+		.iter()
+		.map(|(total, count)| if *count > 0 { total / *count as f64 } else { 0.0 })
+		.collect();
+	let lowest = section_intensities.iter().cloned().fold(f64::INFINITY, f64::min);
+	let highest = section_intensities.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+	let scales: Vec<f64> = section_intensities
+		.iter()
+		.map(|intensity| {
+			let position = if highest > lowest { (intensity - lowest) / (highest - lowest) } else { 0.5 };
+			settings.quiet_scale + (settings.intense_scale - settings.quiet_scale) * position
+		})
+		.collect();
+	//the sections still average the difficulty's target
+	let mean_scale = scales.iter().sum::<f64>() / scales.len() as f64;
+	let targets = scales
+		.iter()
+		.map(|scale| if mean_scale > 0.0 { target_nps * scale / mean_scale } else { target_nps })
+		.collect();
+	Some(DensityPlan {
+		section_beats: settings.section_beats,
+		targets,
+		tolerance: settings.tolerance,
+	})
+}
+
+impl DensityPlan {
+	pub fn target_at(&self, time_in_beats: f64) -> f64 {
+		let section = ((time_in_beats / self.section_beats).max(0.0) as usize).min(self.targets.len() - 1);
+		self.targets[section]
+	}
+}
+
+//notes that aren't bombs from one time (in beats) up to another
+fn notes_between(notes: &[PatternNote], from_beat: f64, to_beat: f64) -> usize {
+	notes
+		.iter()
+		.filter(|note| note.note_type != NOTE_TYPE_BOMB)
+		.filter(|note| note_order(note) >= from_beat && note_order(note) < to_beat)
+		.count()
+}
+
+/*
+* Compare the notes per second over the last section_beats with the target at a peak.
+* More than tolerance over the target drops the peak, over it at all asks for fewer notes,
+* and more than tolerance under it asks for more.
+*/
+pub fn density_adjust(plan: &DensityPlan, notes: &[PatternNote], time_in_beats: f64, beats_per_minute: f64) -> DensityAdjust {
+	let window_start = (time_in_beats - plan.section_beats).max(0.0);
+	let window_sec = ((time_in_beats - window_start) / beats_per_minute) * 60.0;
+	//too little of the map to tell yet
+	if window_sec < 1.0 {
+		return DensityAdjust::AsIs;
+	}
+	let achieved_nps = notes_between(notes, window_start, time_in_beats) as f64 / window_sec;
+	let target_nps = plan.target_at(time_in_beats);
+	if achieved_nps > target_nps * (1.0 + plan.tolerance) {
+		DensityAdjust::Drop
+	} else if achieved_nps > target_nps {
+		DensityAdjust::Fewer
+	} else if achieved_nps < target_nps * (1.0 - plan.tolerance) {
+		DensityAdjust::More
+	} else {
+		DensityAdjust::AsIs
+	}
+}
+
+/*
+* The target and achieved notes per second of every section of the finished map.
+* The last section only runs to the last peak.
+*/
+pub fn section_densities(
+	plan: &DensityPlan,
+	notes: &[PatternNote],
+	end_in_beats: f64,
+	beats_per_minute: f64,
+) -> Vec<SectionDensity> {
+	plan.targets
+		.iter()
+		.enumerate()
+		.map(|(section, target_nps)| {
+			let start_beat = section as f64 * plan.section_beats;
+			let end_beat = (start_beat + plan.section_beats).min(end_in_beats);
+			let section_sec = ((end_beat - start_beat) / beats_per_minute) * 60.0;
+			let achieved_nps = if section_sec > 0.0 {
+				notes_between(notes, start_beat, end_beat) as f64 / section_sec
+			} else {
+				0.0
+			};
+			SectionDensity {
+				start_beat,
+				target_nps: *target_nps,
+				achieved_nps,
+			}
+		})
+		.collect()
+}
+
+/*
+* Keep the patterns with at most the median number of notes when the map wants fewer notes,
+* or at least the median when it wants more. All of them if that leaves none.
+*/
+pub fn prefer_pattern_length(candidates: Vec<&Pattern>, density: DensityAdjust) -> Vec<&Pattern> {
+	if candidates.is_empty() || (density != DensityAdjust::Fewer && density != DensityAdjust::More) {
+		return candidates;
+	}
+	let mut note_counts: Vec<usize> = candidates.iter().map(|pattern| pattern.notes.len()).collect();
+	note_counts.sort_unstable();
+	let median = note_counts[note_counts.len() / 2];
+	let preferred: Vec<&Pattern> = candidates
+		.iter()
+		.filter(|pattern| match density {
+			DensityAdjust::Fewer => pattern.notes.len() <= median,
+			_ => pattern.notes.len() >= median,
+		})
+		.cloned()
+		.collect();
+	if preferred.is_empty() {
+		candidates
+	} else {
+		preferred
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::PatternSource;
+	use crate::test_notes::note;
+
+	//one section of 16 beats at 60 bpm, so a beat is a second
+	fn plan() -> DensityPlan {
+		DensityPlan {
+			section_beats: 16.0,
+			targets: vec![2.0],
+			tolerance: 0.2,
+		}
+	}
+
+	//note_count notes spread over the 16 beats before beat 16
+	fn notes(note_count: usize) -> Vec<PatternNote> {
+		(0..note_count).map(|note_id| note(0, 0, 1, 0, note_id as f64 * 0.25)).collect()
+	}
+
+	fn pattern(note_count: usize) -> Pattern {
+		Pattern {
+			id: format!("{} notes", note_count),
+			template_id: None,
+			source: PatternSource::Library,
+			description: String::new(),
+			notes: notes(note_count),
+			obstacles: Vec::new(),
+		}
+	}
+
+	#[test]
+	fn on_the_target_is_as_is() {
+		assert!(density_adjust(&plan(), &notes(32), 16.0, 60.0) == DensityAdjust::AsIs);
+	}
+
+	#[test]
+	fn over_the_target_wants_fewer() {
+		assert!(density_adjust(&plan(), &notes(33), 16.0, 60.0) == DensityAdjust::Fewer);
+		//2.375 notes per second, just under the tolerance
+		assert!(density_adjust(&plan(), &notes(38), 16.0, 60.0) == DensityAdjust::Fewer);
+	}
+
+	#[test]
+	fn over_the_tolerance_drops() {
+		assert!(density_adjust(&plan(), &notes(39), 16.0, 60.0) == DensityAdjust::Drop);
+	}
+
+	#[test]
+	fn under_the_tolerance_wants_more() {
+		assert!(density_adjust(&plan(), &notes(26), 16.0, 60.0) == DensityAdjust::AsIs);
+		assert!(density_adjust(&plan(), &notes(25), 16.0, 60.0) == DensityAdjust::More);
+	}
+
+	#[test]
+	fn bombs_dont_count() {
+		let mut map_notes = notes(32);
+		map_notes.push(note(0, 0, 1, NOTE_TYPE_BOMB, 15.5));
+		assert!(density_adjust(&plan(), &map_notes, 16.0, 60.0) == DensityAdjust::AsIs);
+	}
+
+	#[test]
+	fn too_little_of_the_map_is_as_is() {
+		assert!(density_adjust(&plan(), &notes(0), 0.5, 60.0) == DensityAdjust::AsIs);
+	}
+
+	#[test]
+	fn fewer_keeps_the_shorter_half() {
+		let patterns: Vec<Pattern> = (1..=4).map(pattern).collect();
+		let candidates: Vec<&Pattern> = patterns.iter().collect();
+		let preferred = prefer_pattern_length(candidates, DensityAdjust::Fewer);
+		let lengths: Vec<usize> = preferred.iter().map(|pattern| pattern.notes.len()).collect();
+		assert_eq!(lengths, vec![1, 2, 3]);
+	}
+
+	#[test]
+	fn more_keeps_the_longer_half() {
+		let patterns: Vec<Pattern> = (1..=4).map(pattern).collect();
+		let candidates: Vec<&Pattern> = patterns.iter().collect();
+		let preferred = prefer_pattern_length(candidates, DensityAdjust::More);
+		let lengths: Vec<usize> = preferred.iter().map(|pattern| pattern.notes.len()).collect();
+		assert_eq!(lengths, vec![3, 4]);
+	}
+
+	#[test]
+	fn as_is_keeps_everything() {
+		let patterns: Vec<Pattern> = (1..=4).map(pattern).collect();
+		let candidates: Vec<&Pattern> = patterns.iter().collect();
+		assert_eq!(prefer_pattern_length(candidates.clone(), DensityAdjust::AsIs).len(), 4);
+		assert_eq!(prefer_pattern_length(candidates, DensityAdjust::Drop).len(), 4);
+	}
+}
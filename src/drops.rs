@@ -0,0 +1,294 @@
+use crate::settings::DropSettings;
+use crate::{Beat, Pattern, PatternWall};
+
+//the lanes a wall burst goes down, either side of the sabers
+const OUTSIDE_LANES: [i64; 2] = [0, 3];
+//every light group, from the back lasers to the center lights
+const LIGHT_EVENT_TYPES: [i64; 5] = [0, 1, 2, 3, 4];
+const LIGHT_RED_FLASH: i64 = 6;
+
+/*
+* A peak where the song comes back in loud, after energy built up to it or after a break.
+* build_up_start is the first peak of the build-up, None for a drop after a break.
+*/
+pub struct Drop {
+	pub peak: usize,
+	pub time_sec: f64,
+	pub build_up_start: Option<usize>,
+}
+
+//the peaks from one time (in seconds) up to another
+fn peaks_between(beats: &[Beat], from_sec: f64, to_sec: f64) -> Vec<&Beat> {
+	beats
+		.iter()
+		.filter(|beat| beat.peak_time_sec >= from_sec && beat.peak_time_sec < to_sec)
+		.collect()
+}
+
+fn mean_energy(peaks: &[&Beat]) -> Option<f64> {
+	if peaks.is_empty() {
+		return None;
+	}
+	Some(peaks.iter().map(|beat| beat.energy.unwrap_or(0.0)).sum::<f64>() / peaks.len() as f64)
+}
+
+//how much onset there is in a stretch of the song, more and stronger peaks both count
+fn onset_total(peaks: &[&Beat]) -> f64 {
+	peaks.iter().map(|beat| beat.onset_strength.unwrap_or(0.0)).sum()
+}
+
+/*
+* Energy and onset density both rise from the first half of the window to the second
+*/
+fn is_build_up(first_half: &[&Beat], second_half: &[&Beat], settings: &DropSettings) -> bool {
+	match (mean_energy(first_half), mean_energy(second_half)) {
+		(Some(first_energy), Some(second_energy)) => {
+			second_energy >= first_energy * settings.build_up_rise
+				&& onset_total(second_half) >= onset_total(first_half) * settings.build_up_rise
+		}
+		_ => false,
+	}
+}
+
+/*
+* Find the drops in the song. A peak is a drop when the half window from it is louder than the song's average
+* and drop_jump times louder than the window before it, and that window was a build-up or a break.
+* The first peak that qualifies is the drop, nothing else counts for min_spacing_seconds after it.
+* There are no drops without onset strength and energy for every peak.
+*/
+pub fn find_drops(beats: &[Beat], settings: &DropSettings) -> Vec<Drop> {
+	let mut drops: Vec<Drop> = Vec::new();
+	if !settings.enabled
+		|| beats.is_empty()
+		|| beats.iter().any(|beat| beat.energy.is_none() || beat.onset_strength.is_none())
+	{
+		return drops;
+	}
+	let all_peaks: Vec<&Beat> = beats.iter().collect();
+	let song_energy = mean_energy(&all_peaks).unwrap_or(0.0);
+	let window = settings.window_seconds;
+
+	for (peak, beat) in beats.iter().enumerate() {
+		let time = beat.peak_time_sec;
+		let too_soon = drops
+			.last()
+			.is_some_and(|last| time - last.time_sec < settings.min_spacing_seconds);
+		if time < window || too_soon {
+			continue;
+		}
+		//the peak itself is always in the half window after it
+		let after_energy = mean_energy(&peaks_between(beats, time, time + window / 2.0)).unwrap_or(0.0);
+		if after_energy < song_energy {
+			continue;
+		}
+		let before = peaks_between(beats, time - window, time);
+		let before_energy = mean_energy(&before);
+		if before_energy.is_some_and(|energy| after_energy < energy * settings.drop_jump) {
+			continue;
+		}
+
+		let after_break = before_energy.is_none_or(|energy| energy < song_energy * settings.break_level);
+		let first_half = peaks_between(beats, time - window, time - window / 2.0);
+		let second_half = peaks_between(beats, time - window / 2.0, time);
+		if !after_break && !is_build_up(&first_half, &second_half, settings) {
+			continue;
+		}
+		let build_up_start = if after_break {
+			None
+		} else {
+			beats.iter().position(|beat| beat.peak_time_sec >= time - window)
+		};
+		drops.push(Drop {
+			peak,
+			time_sec: time,
+			build_up_start,
+		});
+	}
+	drops
+}
+
+/*
+* Whether a time (in seconds) is in the thin out before a drop, where peaks are left empty
+*/
+pub fn is_thinned_out(drops: &[Drop], thin_out_seconds: f64, time_sec: f64) -> bool {
+	drops
+		.iter()
+		.any(|drop| time_sec >= drop.time_sec - thin_out_seconds && time_sec < drop.time_sec)
+}
+
+/*
+* Where the notes of a pattern starting at start_sec are cut short, the start of the next drop's thin out.
+* None for a pattern that starts inside the thin out, the peaks there are left empty rather than cut.
+*/
+pub fn thin_out_cut(drops: &[Drop], thin_out_seconds: f64, start_sec: f64) -> Option<f64> {
+	drops
+		.iter()
+		.find(|drop| drop.time_sec > start_sec)
+		.map(|drop| drop.time_sec - thin_out_seconds)
+		.filter(|cut_time| *cut_time > start_sec)
+}
+
+/*
+* Walls down the outside lanes for the length of the burst, leaving out a lane the pattern has notes in
+*/
+pub fn burst_walls(pattern: &Pattern, wall_burst_beats: f64) -> Vec<PatternWall> {
+	if wall_burst_beats <= 0.0 {
+		return Vec::new();
+	}
+	OUTSIDE_LANES
+		.iter()
+		.filter(|lane| !pattern.notes.iter().any(|note| note.x == **lane))
+		.map(|lane| PatternWall {
+			x: *lane,
+			y: 0,
+			duration: wall_burst_beats,
+			padding: 0.0,
+			width: 1.0,
+			wall_type: 0,
+			description: "wall burst".to_owned(),
+			wall_time_rel: 0,
+			wall_time: Some(0.0),
+		})
+		.collect()
+}
+
+/*
+* A red flash on every light group at each drop, as json to go after the other events
+*/
+pub fn lighting_hit_events(drops: &[Drop], beats_per_minute: f64) -> String {
+	let mut events = String::new();
+	for drop in drops {
+		let time_in_beats = (drop.time_sec / 60.0) * beats_per_minute;
+		for event_type in LIGHT_EVENT_TYPES.iter() {
+			events.push_str(&format!(
+				",{{
+				\"_time\":{},
+				\"_type\":{},
+				\"_value\":{}
+			}}",
+				time_in_beats, event_type, LIGHT_RED_FLASH
+			));
+		}
+	}
+	events
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	//a peak every second with the given energy, onset strength rises and falls with it
+	fn beats(energies: &[f64]) -> Vec<Beat> {
+		energies
+			.iter()
+			.enumerate()
+			.map(|(id, energy)| Beat {
+				peak_time_sec: id as f64,
+				id,
+				pitch: 0.0,
+				onset_strength: Some(*energy),
+				energy: Some(*energy),
+				chroma: Vec::new(),
+			})
+			.collect()
+	}
+
+	//a two second window, so the half window after a peak is just the peak
+	fn settings() -> DropSettings {
+		DropSettings {
+			window_seconds: 2.0,
+			..DropSettings::default()
+		}
+	}
+
+	//the energy of the peak at each second, then energy loud from loud_from on up to 20 seconds
+	fn song(start: &[f64], loud_from: usize, loud: f64) -> Vec<f64> {
+		let mut energies = start.to_vec();
+		energies.resize(loud_from, start[start.len() - 1]);
+		energies.resize(20, loud);
+		energies
+	}
+
+	#[test]
+	fn drop_after_a_break() {
+		let energies = song(&[1.0, 1.0, 1.0, 1.0, 1.0, 1.0, 0.1], 10, 2.0);
+		let drops = find_drops(&beats(&energies), &settings());
+		assert_eq!(drops.len(), 1);
+		assert_eq!(drops[0].peak, 10);
+		assert!(drops[0].build_up_start.is_none());
+	}
+
+	#[test]
+	fn drop_after_a_build_up() {
+		let energies = song(&[0.5, 0.5, 0.5, 0.5, 0.5, 0.5, 0.5, 0.5, 0.55, 0.9], 10, 3.0);
+		let drops = find_drops(&beats(&energies), &DropSettings {
+			break_level: 0.0,
+			..settings()
+		});
+		assert_eq!(drops.len(), 1);
+		assert_eq!(drops[0].peak, 10);
+		assert_eq!(drops[0].build_up_start, Some(8));
+	}
+
+	#[test]
+	fn no_drop_in_a_steady_song() {
+		assert!(find_drops(&beats(&[1.0; 20]), &settings()).is_empty());
+	}
+
+	#[test]
+	fn no_drop_without_a_big_enough_jump() {
+		let energies = song(&[0.5, 0.5, 0.5, 0.5, 0.5, 0.5, 0.5, 0.5, 0.55, 0.9], 10, 1.0);
+		let drops = find_drops(&beats(&energies), &DropSettings {
+			break_level: 0.0,
+			..settings()
+		});
+		assert!(drops.is_empty());
+	}
+
+	#[test]
+	fn one_drop_per_min_spacing() {
+		let mut energies = song(&[1.0, 1.0, 1.0, 1.0, 1.0, 1.0, 0.1], 10, 2.0);
+		//another break and drop 6 seconds later
+		energies[13] = 0.1;
+		energies[14] = 0.1;
+		let spaced_drops = find_drops(&beats(&energies), &settings());
+		assert_eq!(spaced_drops.len(), 1);
+		let drops = find_drops(&beats(&energies), &DropSettings {
+			min_spacing_seconds: 4.0,
+			..settings()
+		});
+		assert_eq!(drops.iter().map(|drop| drop.peak).collect::<Vec<usize>>(), vec![10, 15]);
+	}
+
+	#[test]
+	fn no_drops_without_energy() {
+		let mut peaks = beats(&song(&[1.0, 1.0, 1.0, 1.0, 1.0, 1.0, 0.1], 10, 2.0));
+		peaks[3].energy = None;
+		assert!(find_drops(&peaks, &settings()).is_empty());
+		assert!(find_drops(&[], &settings()).is_empty());
+	}
+
+	#[test]
+	fn thin_out_cut_before_the_next_drop() {
+		let drops = [Drop {
+			peak: 10,
+			time_sec: 10.0,
+			build_up_start: None,
+		}];
+		assert_eq!(thin_out_cut(&drops, 2.0, 5.0), Some(8.0));
+		//inside the thin out, and on or after the drop
+		assert_eq!(thin_out_cut(&drops, 2.0, 8.0), None);
+		assert_eq!(thin_out_cut(&drops, 2.0, 9.0), None);
+		assert_eq!(thin_out_cut(&drops, 2.0, 10.0), None);
+	}
+
+	#[test]
+	fn no_drops_when_disabled() {
+		let energies = song(&[1.0, 1.0, 1.0, 1.0, 1.0, 1.0, 0.1], 10, 2.0);
+		let drops = find_drops(&beats(&energies), &DropSettings {
+			enabled: false,
+			..settings()
+		});
+		assert!(drops.is_empty());
+	}
+}
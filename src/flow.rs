@@ -0,0 +1,243 @@
+use crate::parity::breaks_parity;
+use crate::{Pattern, PatternNote, PatternSource};
+
+//how many columns or layers a hand can move between the end of one pattern and the start of the next
+const MAX_ENTRY_TRAVEL: i64 = 2;
+const CUT_DOT: i64 = 8;
+//the cut that undoes each cut direction, indexed by cut direction
+const OPPOSITE_CUTS: [i64; 9] = [1, 0, 3, 2, 7, 6, 5, 4, 8];
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Swing {
+	Forehand,
+	Backhand,
+}
+
+impl Swing {
+	pub fn flipped(self) -> Swing {
+		match self {
+			Swing::Forehand => Swing::Backhand,
+			Swing::Backhand => Swing::Forehand,
+		}
+	}
+}
+
+/*
+* Where a saber is and how it last swung.
+* swing is None until the hand has made a swing that commits it to a side (dots don't).
+*/
+#[derive(Clone, Copy)]
+pub struct HandState {
+	pub x: i64,
+	pub y: i64,
+	pub cut_direction: i64,
+	pub swing: Option<Swing>,
+}
+
+/*
+* The first and last swing of each hand in a pattern, index 0 is the left saber and 1 the right.
+* A hand that has no notes in the pattern has no entry or exit.
+*/
+pub struct PatternFlow {
+	pub entry: [Option<HandState>; 2],
+	pub exit: [Option<HandState>; 2],
+}
+
+/*
+* Downward cuts are forehands and upward cuts backhands for both hands.
+* Sideways cuts towards the body are forehands, so left cuts are forehands for the right saber
+* and right cuts are forehands for the left saber. Dots can be hit either way.
+*/
+pub fn swing_for_cut(note_type: i64, cut_direction: i64) -> Option<Swing> {
+	match cut_direction {
+		1 | 6 | 7 => Some(Swing::Forehand),
+		0 | 4 | 5 => Some(Swing::Backhand),
+		2 if note_type == 1 => Some(Swing::Forehand),
+		3 if note_type == 0 => Some(Swing::Forehand),
+		2 | 3 => Some(Swing::Backhand),
+		_ => None,
+	}
+}
+
+pub fn opposite_cut(cut_direction: i64) -> i64 {
+	OPPOSITE_CUTS
+		.get(cut_direction as usize)
+		.cloned()
+		.unwrap_or(CUT_DOT)
+}
+
+/*
+* The swing a note makes when it follows prev_swing on the same hand
+*/
+pub fn next_swing(prev_swing: Option<Swing>, note_type: i64, cut_direction: i64) -> Option<Swing> {
+	match swing_for_cut(note_type, cut_direction) {
+		Some(swing) => Some(swing),
+		None => prev_swing.map(Swing::flipped),
+	}
+}
+
+pub fn hand_state_after(prev: &HandState, note: &PatternNote) -> HandState {
+	HandState {
+		x: note.x,
+		y: note.y,
+		cut_direction: note.cut_direction,
+		swing: next_swing(prev.swing, note.note_type, note.cut_direction),
+	}
+}
+
+//notes closer together than this (in beats, or peak offsets) are at the same time, a saber cuts them with one swing
+pub const SAME_TIME_TOLERANCE: f64 = 0.001;
+//the grid is 4 columns wide
+pub const GRID_COLUMNS: i64 = 4;
+
+//notes are compared by peak offset, or by beat for patterns timed in fractional beats
+pub fn note_order(note: &PatternNote) -> f64 {
+	match note.beat_time {
+		Some(beat_time) => beat_time,
+		None => note.beat_time_rel as f64,
+	}
+}
+
+pub fn pattern_flow(pattern: &Pattern) -> PatternFlow {
+	let mut hand_notes: Vec<&PatternNote> = pattern
+		.notes
+		.iter()
+		.filter(|note| note.note_type == 0 || note.note_type == 1)
+		.collect();
+	hand_notes.sort_by(|a, b| note_order(a).partial_cmp(&note_order(b)).unwrap());
+
+	let mut entry: [Option<HandState>; 2] = [None, None];
+	let mut exit: [Option<HandState>; 2] = [None, None];
+	for note in hand_notes {
+		let hand = note.note_type as usize;
+		let state = match exit[hand] {
+			Some(prev) => hand_state_after(&prev, note),
+			None => HandState {
+				x: note.x,
+				y: note.y,
+				cut_direction: note.cut_direction,
+				swing: swing_for_cut(note.note_type, note.cut_direction),
+			},
+		};
+		if entry[hand].is_none() {
+			entry[hand] = Some(state);
+		}
+		exit[hand] = Some(state);
+	}
+	PatternFlow { entry, exit }
+}
+
+/*
+* A hand can go into its next swing if it alternates forehand and backhand
+* and doesn't have to travel across the grid to get there
+*/
+pub fn hand_fits(current: &HandState, entry: &HandState) -> bool {
+	let swing_alternates = match (current.swing, entry.swing) {
+		(Some(current_swing), Some(entry_swing)) => current_swing != entry_swing,
+		_ => true,
+	};
+	let travel = (current.x - entry.x).abs().max((current.y - entry.y).abs());
+	swing_alternates && travel <= MAX_ENTRY_TRAVEL
+}
+
+pub fn flow_fits(hand_states: &[HandState; 2], flow: &PatternFlow) -> bool {
+	hand_states
+		.iter()
+		.zip(flow.entry.iter())
+		.all(|(current, entry)| match entry {
+			Some(entry) => hand_fits(current, entry),
+			None => true,
+		})
+}
+
+/*
+* The cut of a bridge note, the opposite of the entry's cut. None if that would swing the same way as the hand's
+* last swing, or make the entry swing the same way as the bridge, as a bridge can't fix a hand that only travels too far.
+*/
+fn bridge_cut(current: &HandState, entry: &HandState, note_type: i64) -> Option<i64> {
+	let cut_direction = opposite_cut(entry.cut_direction);
+	let bridge_swing = next_swing(current.swing, note_type, cut_direction);
+	if !breaks_parity(current.swing, note_type, cut_direction)
+		&& !breaks_parity(bridge_swing, note_type, entry.cut_direction)
+	{
+		Some(cut_direction)
+	} else {
+		None
+	}
+}
+
+/*
+* A single step that takes each hand that doesn't fit from where it is into the pattern's entry.
+* The bridge note sits between the two positions, beat_time (in beats) from the start of the pattern it's placed with.
+*/
+pub fn bridge_pattern(hand_states: &[HandState; 2], flow: &PatternFlow, beat_time: f64) -> Option<Pattern> {
+	let mut notes: Vec<PatternNote> = Vec::new();
+	for (hand, (current, entry)) in hand_states.iter().zip(flow.entry.iter()).enumerate() {
+		let entry = match entry {
+			Some(entry) if !hand_fits(current, entry) => entry,
+			_ => continue,
+		};
+		let x = (current.x + entry.x) / 2;
+		let y = (current.y + entry.y) / 2;
+		if notes.iter().any(|note| note.x == x && note.y == y) {
+			continue;
+		}
+		let cut_direction = match bridge_cut(current, entry, hand as i64) {
+			Some(cut_direction) => cut_direction,
+			None => continue,
+		};
+		notes.push(PatternNote {
+			x,
+			y,
+			cut_direction,
+			note_type: hand as i64,
+			description: "Bridge".to_owned(),
+			beat_time_rel: 0,
+			beat_time: Some(beat_time),
+		});
+	}
+	if notes.is_empty() {
+		return None;
+	}
+	Some(Pattern {
+		id: "bridge".to_owned(),
+		template_id: None,
+		source: PatternSource::Bridge,
+		description: "Bridge".to_owned(),
+		notes,
+		obstacles: Vec::new(),
+	})
+}
+
+pub fn describe_hand_state(state: &Option<HandState>) -> String {
+	match state {
+		Some(state) => format!(
+			"{},{} cut {} {}",
+			state.x,
+			state.y,
+			state.cut_direction,
+			match state.swing {
+				Some(Swing::Forehand) => "forehand",
+				Some(Swing::Backhand) => "backhand",
+				None => "either",
+			}
+		),
+		None => "-".to_owned(),
+	}
+}
+
+/*
+* Move each saber through the notes of a pattern that has just been placed
+*/
+pub fn advance_hand_states(hand_states: &mut [HandState; 2], pattern: &Pattern) {
+	let mut hand_notes: Vec<&PatternNote> = pattern
+		.notes
+		.iter()
+		.filter(|note| note.note_type == 0 || note.note_type == 1)
+		.collect();
+	hand_notes.sort_by(|a, b| note_order(a).partial_cmp(&note_order(b)).unwrap());
+	for note in hand_notes {
+		let hand = note.note_type as usize;
+		hand_states[hand] = hand_state_after(&hand_states[hand], note);
+	}
+}
@@ -0,0 +1,130 @@
+use crate::bombs::{swing_path_cells, travel_cells};
+use crate::flow::{note_order, SAME_TIME_TOLERANCE};
+use crate::PatternNote;
+
+//a saber's last or next note only says where it is if it's this close (in beats or pattern steps)
+const HAND_POSITION_WINDOW: f64 = 1.0;
+
+pub struct HandIssue<'a> {
+	pub rule: &'static str,
+	pub note: &'a PatternNote,
+	pub other: &'a PatternNote,
+}
+
+/*
+* Where a saber is at a time, worked out from its notes either side.
+* Between two notes the saber is somewhere on the straight line from one to the other.
+*/
+fn hand_x_at(hand_notes: &[&PatternNote], time: f64) -> Option<f64> {
+	let prev_note = hand_notes
+		.iter()
+		.rev()
+		.find(|note| note_order(note) <= time + SAME_TIME_TOLERANCE)
+		.filter(|note| time - note_order(note) <= HAND_POSITION_WINDOW);
+	let next_note = hand_notes
+		.iter()
+		.find(|note| note_order(note) > time + SAME_TIME_TOLERANCE)
+		.filter(|note| note_order(note) - time <= HAND_POSITION_WINDOW);
+	match (prev_note, next_note) {
+		(Some(prev_note), Some(next_note)) => {
+			let prev_time = note_order(prev_note);
+			let progress = (time - prev_time) / (note_order(next_note) - prev_time);
+			Some(prev_note.x as f64 + (next_note.x - prev_note.x) as f64 * progress.max(0.0))
+		}
+		(Some(note), None) | (None, Some(note)) => Some(note.x as f64),
+		(None, None) => None,
+	}
+}
+
+/*
+* Check both sabers against each other:
+* crossover, a saber's note is on the far side of where the other saber is,
+* hand clash, two notes cut at the same time swing into each other,
+* swing through, a saber moving between two notes passes through a swing the other saber makes in between.
+*/
+pub fn find_hand_issues(notes: &[PatternNote], allow_crossovers: bool) -> Vec<HandIssue<'_>> {
+	let mut hands: [Vec<&PatternNote>; 2] = [Vec::new(), Vec::new()];
+	for note in notes.iter().filter(|note| note.note_type == 0 || note.note_type == 1) {
+		hands[note.note_type as usize].push(note);
+	}
+	for hand_notes in hands.iter_mut() {
+		hand_notes.sort_by(|a, b| note_order(a).partial_cmp(&note_order(b)).unwrap());
+	}
+
+	let mut issues = Vec::new();
+	for hand in 0..2 {
+		let other_hand = 1 - hand;
+		for (note_id, note) in hands[hand].iter().enumerate() {
+			let time = note_order(note);
+			let simultaneous_notes: Vec<&PatternNote> = hands[other_hand]
+				.iter()
+				.filter(|other_note| (note_order(other_note) - time).abs() <= SAME_TIME_TOLERANCE)
+				.cloned()
+				.collect();
+			//crossed notes cut together are only reported once, from the left saber
+			if !allow_crossovers && (hand == 0 || simultaneous_notes.is_empty()) {
+				if let Some(other_x) = hand_x_at(&hands[other_hand], time) {
+					//the left saber has to stay left of the right saber
+					let crossed = if hand == 0 {
+						note.x as f64 > other_x
+					} else {
+						(note.x as f64) < other_x
+					};
+					//report it against the other saber's nearest note
+					let other_note = hands[other_hand].iter().min_by(|a, b| {
+						(note_order(a) - time)
+							.abs()
+							.partial_cmp(&(note_order(b) - time).abs())
+							.unwrap()
+					});
+					if let (true, Some(other_note)) = (crossed, other_note) {
+						issues.push(HandIssue {
+							rule: "crossover",
+							note,
+							other: other_note,
+						});
+					}
+				}
+			}
+
+			//only check each pair of simultaneous notes once, from the left saber
+			if hand == 0 {
+				for other_note in simultaneous_notes {
+					let swings_into = |from: &PatternNote, to: &PatternNote| {
+						swing_path_cells(from).contains(&(to.x, to.y))
+					};
+					if swings_into(note, other_note) || swings_into(other_note, note) {
+						issues.push(HandIssue {
+							rule: "hand clash",
+							note,
+							other: other_note,
+						});
+					}
+				}
+			}
+
+			if let Some(next_note) = hands[hand].get(note_id + 1) {
+				let next_time = note_order(next_note);
+				if next_time - time > HAND_POSITION_WINDOW {
+					continue;
+				}
+				//the cells the saber crosses, without the two notes at either end
+				let path = travel_cells(note, next_note);
+				let path_between = if path.len() > 2 { &path[1..path.len() - 1] } else { &[] };
+				for other_note in hands[other_hand].iter().filter(|other_note| {
+					let other_time = note_order(other_note);
+					other_time > time + SAME_TIME_TOLERANCE && other_time < next_time - SAME_TIME_TOLERANCE
+				}) {
+					if path_between.contains(&(other_note.x, other_note.y)) {
+						issues.push(HandIssue {
+							rule: "swing through",
+							note: next_note,
+							other: other_note,
+						});
+					}
+				}
+			}
+		}
+	}
+	issues
+}
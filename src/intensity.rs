@@ -0,0 +1,74 @@
+use crate::settings::IntensitySettings;
+use crate::Beat;
+
+/*
+* Where each value sits between the smallest and largest of them, from 0 to 1
+*/
+fn normalized(values: &[f64]) -> Vec<f64> {
+	let lowest = values.iter().cloned().fold(f64::INFINITY, f64::min);
+	let highest = values.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+	values
+		.iter()
+		.map(|value| {
+			if highest > lowest {
+				(value - lowest) / (highest - lowest)
+			} else {
+				0.0
+			}
+		})
+		.collect()
+}
+
+//how many peaks there are in the window around each peak
+fn onset_density(beats: &[Beat], window_seconds: f64) -> Vec<f64> {
+	beats
+		.iter()
+		.map(|beat| {
+			beats
+				.iter()
+				.filter(|other| (other.peak_time_sec - beat.peak_time_sec).abs() <= window_seconds / 2.0)
+				.count() as f64
+		})
+		.collect()
+}
+
+/*
+* How intense the music is at each peak, from 0 to 1: the weighted mean of its onset strength,
+* the onset density around it and its energy, each scaled to the song's range.
+* Onset strength and energy are left out when peak_times.txt doesn't have them.
+*/
+pub fn intensity_scores(beats: &[Beat], settings: &IntensitySettings) -> Vec<f64> {
+	let mut parts: Vec<(f64, Vec<f64>)> = vec![(
+		settings.density_weight,
+		normalized(&onset_density(beats, settings.density_window_seconds)),
+	)];
+	if beats.iter().all(|beat| beat.onset_strength.is_some()) {
+		let onsets: Vec<f64> = beats.iter().map(|beat| beat.onset_strength.unwrap_or(0.0)).collect();
+		parts.push((settings.onset_weight, normalized(&onsets)));
+	}
+	if beats.iter().all(|beat| beat.energy.is_some()) {
+		let energies: Vec<f64> = beats.iter().map(|beat| beat.energy.unwrap_or(0.0)).collect();
+		parts.push((settings.energy_weight, normalized(&energies)));
+	}
+
+	let total_weight: f64 = parts.iter().map(|(weight, _)| weight).sum();
+	(0..beats.len())
+		.map(|peak| {
+			if total_weight <= 0.0 {
+				return 0.0;
+			}
+			parts.iter().map(|(weight, values)| weight * values[peak]).sum::<f64>() / total_weight
+		})
+		.collect()
+}
+
+/*
+* Which pool an intensity picks from, 0 for easy, 1 for normal and 2 for hard
+*/
+pub fn pool_level(settings: &IntensitySettings, intensity: f64) -> usize {
+	settings
+		.pool_starts
+		.iter()
+		.filter(|pool_start| intensity >= **pool_start)
+		.count()
+}
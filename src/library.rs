@@ -0,0 +1,230 @@
+use crate::settings::LibrarySettings;
+use crate::{parse_pattern_json, PatternCollection};
+use glob::glob;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+//the contents of src/patterns when the generator was built, written by build.rs
+mod embedded {
+	include!(concat!(env!("OUT_DIR"), "/embedded_patterns.rs"));
+}
+
+const MANIFEST_FILE: &str = "manifest.json";
+const POOLS: [&str; 3] = ["easy", "normal", "hard"];
+
+/*
+* Says which folders of a library are loaded and into which pool.
+* A library root can have one as manifest.json, and a project can have one that's applied on top of every root.
+*/
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct LibraryManifest {
+	packs: Vec<PackManifest>,
+	//keyed by pattern id, e.g. "normal/^"
+	overrides: BTreeMap<String, PatternOverride>,
+}
+
+#[derive(Deserialize)]
+struct PackManifest {
+	name: String,
+	//packs are enabled unless they say otherwise
+	enabled: Option<bool>,
+	#[serde(default)]
+	folders: Vec<String>,
+	//easy, normal or hard, defaults to the pack name
+	pool: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+struct PatternOverride {
+	enabled: Option<bool>,
+	pool: Option<String>,
+}
+
+struct LibraryEntry {
+	pack: String,
+	pool: String,
+	enabled: bool,
+	pattern_json: String,
+}
+
+/*
+* Load every enabled pattern from the embedded library and the library roots, or say which file can't be read.
+* Roots are loaded in order and a pattern replaces any earlier pattern with the same id.
+*/
+pub fn load_library(settings: &LibrarySettings) -> Result<PatternCollection, String> {
+	let mut entries: BTreeMap<String, LibraryEntry> = BTreeMap::new();
+	if settings.use_embedded {
+		let files = embedded::EMBEDDED_PATTERNS
+			.iter()
+			.map(|(path, contents)| (path.to_string(), contents.to_string()))
+			.collect();
+		add_library_root(&mut entries, files)?;
+	}
+	for root in &settings.roots {
+		let root_path = expand_home(root);
+		if root_path.is_dir() {
+			add_library_root(&mut entries, read_library_files(&root_path)?)?;
+		}
+	}
+	if let Some(manifest_path) = &settings.manifest {
+		if Path::new(manifest_path).exists() {
+			let manifest_json = fs::read_to_string(manifest_path)
+				.map_err(|error| format!("Could not read library manifest {}: {}", manifest_path, error))?;
+			apply_manifest(&mut entries, &parse_manifest(&manifest_json, manifest_path)?);
+		}
+	}
+
+	let mut pattern_collection = PatternCollection {
+		easy_patterns: Vec::new(),
+		normal_patterns: Vec::new(),
+		hard_patterns: Vec::new(),
+	};
+	for (pattern_id, entry) in entries.iter().filter(|(_, entry)| entry.enabled) {
+		let patterns = parse_pattern_json(&entry.pattern_json, pattern_id)?;
+		match entry.pool.as_str() {
+			"easy" => pattern_collection.easy_patterns.extend(patterns),
+			"normal" => pattern_collection.normal_patterns.extend(patterns),
+			"hard" => pattern_collection.hard_patterns.extend(patterns),
+			pool => println!("Skipping {} from pack {}, unknown pool {}", pattern_id, entry.pack, pool),
+		}
+	}
+	//every pool is picked from somewhere in a song, so none of them can be left empty
+	let pools = [
+		("easy", &pattern_collection.easy_patterns),
+		("normal", &pattern_collection.normal_patterns),
+		("hard", &pattern_collection.hard_patterns),
+	];
+	if let Some((pool, _)) = pools.iter().find(|(_, patterns)| patterns.is_empty()) {
+		return Err(format!(
+			"The {} pool has no patterns, enable a pack or pattern for it in the library manifest",
+			pool
+		));
+	}
+	println!(
+		"Loaded patterns: {} easy, {} normal, {} hard",
+		pattern_collection.easy_patterns.len(),
+		pattern_collection.normal_patterns.len(),
+		pattern_collection.hard_patterns.len()
+	);
+	Ok(pattern_collection)
+}
+
+/*
+* Every json file in a library folder as (path inside the library, contents)
+*/
+fn read_library_files(root_path: &Path) -> Result<Vec<(String, String)>, String> {
+	let mut files = Vec::new();
+	let root_glob = format!("{}/**/*.json", root_path.display());
+	for entry in glob(&root_glob).expect("Failed to read glob pattern") {
+		match entry {
+			Ok(path) => {
+				let relative_path: Vec<String> = path
+					.strip_prefix(root_path)
+					.unwrap_or(&path)
+					.components()
+					.map(|component| component.as_os_str().to_string_lossy().to_string())
+					.collect();
+				let contents = fs::read_to_string(&path)
+					.map_err(|error| format!("Could not read pattern file {}: {}", path.display(), error))?;
+				files.push((relative_path.join("/"), contents));
+			}
+			Err(e) => println!("{:?}", e),
+		}
+	}
+	Ok(files)
+}
+
+fn add_library_root(entries: &mut BTreeMap<String, LibraryEntry>, files: Vec<(String, String)>) -> Result<(), String> {
+	let manifest = match files.iter().find(|(path, _)| path == MANIFEST_FILE) {
+		Some((path, manifest_json)) => parse_manifest(manifest_json, path)?,
+		None => default_manifest(),
+	};
+	for (path, contents) in files {
+		let (folder, file_name) = match path.rfind('/') {
+			Some(split) => (&path[..split], &path[split + 1..]),
+			None => continue,
+		};
+		let pack = match manifest
+			.packs
+			.iter()
+			.find(|pack| pack.folders.iter().any(|pack_folder| pack_folder == folder))
+		{
+			Some(pack) => pack,
+			None => continue,
+		};
+		let pattern_id = format!("{}/{}", folder, file_name.trim_end_matches(".json"));
+		entries.insert(
+			pattern_id,
+			LibraryEntry {
+				pack: pack.name.to_owned(),
+				pool: pack.pool.to_owned().unwrap_or_else(|| pack.name.to_owned()),
+				enabled: pack.enabled.unwrap_or(true),
+				pattern_json: contents,
+			},
+		);
+	}
+	apply_overrides(entries, &manifest);
+	Ok(())
+}
+
+/*
+* A project manifest changes packs by name and patterns by id, it doesn't add folders
+*/
+fn apply_manifest(entries: &mut BTreeMap<String, LibraryEntry>, manifest: &LibraryManifest) {
+	for pack in &manifest.packs {
+		for entry in entries.values_mut().filter(|entry| entry.pack == pack.name) {
+			if let Some(enabled) = pack.enabled {
+				entry.enabled = enabled;
+			}
+			if let Some(pool) = &pack.pool {
+				entry.pool = pool.to_owned();
+			}
+		}
+	}
+	apply_overrides(entries, manifest);
+}
+
+fn apply_overrides(entries: &mut BTreeMap<String, LibraryEntry>, manifest: &LibraryManifest) {
+	for (pattern_id, pattern_override) in &manifest.overrides {
+		if let Some(entry) = entries.get_mut(pattern_id) {
+			if let Some(enabled) = pattern_override.enabled {
+				entry.enabled = enabled;
+			}
+			if let Some(pool) = &pattern_override.pool {
+				entry.pool = pool.to_owned();
+			}
+		}
+	}
+}
+
+fn parse_manifest(manifest_json: &str, manifest_path: &str) -> Result<LibraryManifest, String> {
+	serde_json::from_str(manifest_json)
+		.map_err(|error| format!("Could not parse library manifest {}: {}", manifest_path, error))
+}
+
+//without a manifest the easy, normal and hard folders go into the pools with the same name
+fn default_manifest() -> LibraryManifest {
+	LibraryManifest {
+		packs: POOLS
+			.iter()
+			.map(|pool| PackManifest {
+				name: pool.to_string(),
+				enabled: None,
+				folders: vec![pool.to_string()],
+				pool: None,
+			})
+			.collect(),
+		overrides: BTreeMap::new(),
+	}
+}
+
+fn expand_home(root: &str) -> PathBuf {
+	match (root.strip_prefix("~/"), env::var("HOME")) {
+		(Some(rest), Ok(home)) => Path::new(&home).join(rest),
+		_ => PathBuf::from(root),
+	}
+}
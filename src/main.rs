@@ -3,22 +3,101 @@ extern crate ogg;
 extern crate rand;
 extern crate zip;
 
-use glob::glob;
-use rand::distributions::{Distribution, Uniform};
-use rand::Rng;
+mod beatmap;
+mod bombs;
+mod density;
+mod drops;
+mod flow;
+mod hands;
+mod intensity;
+mod library;
+mod model;
+mod parity;
+mod phrases;
+mod pitch;
+mod placement;
+mod preview;
+mod primitives;
+mod refine;
+mod repetition;
+mod report;
+mod search;
+mod settings;
+mod swing;
+mod swing_rate;
+mod template;
+#[cfg(test)]
+mod test_notes;
+mod validate;
+mod vision;
+mod watch;
+
+use bombs::{add_dynamic_bombs, bomb_is_safe, map_note_times, NOTE_TYPE_BOMB};
+use density::{density_adjust, density_plan, prefer_pattern_length, section_densities, DensityAdjust, DensityPlan};
+use drops::{burst_walls, find_drops, is_thinned_out, lighting_hit_events, thin_out_cut, Drop};
+use flow::{
+	advance_hand_states, bridge_pattern, flow_fits, hand_state_after, note_order, opposite_cut, pattern_flow,
+	HandState, Swing,
+};
+use hands::find_hand_issues;
+use intensity::{intensity_scores, pool_level};
+use library::load_library;
+use model::{load_transition_model, sample_weighted, TransitionModel};
+use parity::{breaks_parity, is_reset_gap, pattern_keeps_parity};
+use phrases::{find_phrase_repeats, mirror_pattern};
+use pitch::{hand_for_position, pitch_hint, pitch_position, PitchHint};
+use placement::{solve_placement, PlacementConstraints};
+use primitives::{choose_primitive, generate_primitive, Primitive, PrimitiveParams};
+use refine::{notes_per_second, refine_map};
+use repetition::{choose_pattern, RepetitionTracker};
+use report::GenerationStats;
+use search::{beam_search, greedy_search};
+use settings::{get_generator_settings, DropResponse, GeneratorSettings, PoolMode, SearchMode};
+use swing::{is_uncomfortable, swing_transition};
+use swing_rate::{fix_swing_rate, is_occupied, turn_for_parity, RateFix};
+use template::{expand_template, is_template, template_base_id, PatternTemplate};
+use vision::{fix_vision_block, hides, vision_window_beats, VisionFix};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::env;
 use std::fs;
 use std::fs::File;
 use std::io::BufRead;
 use std::io::BufReader;
-use std::io::Read;
 use std::io::{Seek, Write};
+use std::path::Path;
+use std::rc::Rc;
 use zip::result::ZipResult;
 use zip::write::{FileOptions, ZipWriter};
 
 const CHANCE_FOR_DYNAMIC_PATTERN: i64 = 50;
+const MAX_DYNAMIC_PATTERN_NOTES: usize = 8;
+//dynamic patterns and primitives get 4 to 8 steps, fewer when the map is over its density target and more when it's under
+const MIN_PATTERN_STEPS: usize = 4;
+const SHORT_PATTERN_STEPS: usize = 2;
+const LONG_PATTERN_STEPS: usize = 6;
+//how many times a dynamic pattern is generated again when its sabers cross or clash
+const MAX_DYNAMIC_PATTERN_ATTEMPTS: usize = 5;
+//how many times a note in a dynamic pattern is picked again when the swing to it is uncomfortable
+const MAX_SWING_ATTEMPTS: usize = 5;
 const MIN_BEAT_SPACING_TIME: f64 = 0.05;
+//the lines peak_times.txt can have: time, id and pitch, then onset strength and energy, then the 12 chroma values
+const PEAK_COLUMNS: [usize; 3] = [3, 5, 17];
+//patterns timed in fractional beats start on the nearest 1/4 beat
+const PATTERN_GRID_DIVISION: f64 = 4.0;
+//primitives make a swing at least every 1/4 beat and at most every 2 beats
+const MIN_PRIMITIVE_STEP_BEATS: f64 = 0.25;
+const MAX_PRIMITIVE_STEP_BEATS: f64 = 2.0;
+//a drop without a signature pattern from the library gets a jump down and back up, a beat apart,
+//just the jump down when the map is over its density target and one more jump down when it's under
+const SIGNATURE_JUMP_STEPS: usize = 2;
+const SHORT_SIGNATURE_JUMP_STEPS: usize = 1;
+const LONG_SIGNATURE_JUMP_STEPS: usize = 3;
+const SIGNATURE_JUMP_STEP_BEATS: f64 = 1.0;
+//used when info.dat doesn't give the ExpertPlus map a note jump speed
+const DEFAULT_NOTE_JUMP_SPEED: f64 = 18.0;
 struct CutDirection {
 	cut_direction: i64,
 	x_dir: i64,
@@ -82,30 +161,122 @@ const CUT_OMNI: CutDirection = CutDirection {
 const CUT_DIRECTIONS: [CutDirection; 9] = [
 	CUT_UP, CUT_DOWN, CUT_LEFT, CUT_RIGHT, CUT_NW, CUT_NE, CUT_SW, CUT_SE, CUT_OMNI,
 ];
-fn get_next_cut(cut_direction: &CutDirection) -> &CutDirection {
-	let random_transition_index = rand::thread_rng().gen_range(0, CUT_DIRECTIONS.len());
-	let mut result: &CutDirection = &CUT_OMNI;
-	//get a random cut direction from the possible transitions of the last CutDirection
-	let direction = cut_direction.possible_transitions[random_transition_index];
-	for cd in CUT_DIRECTIONS.iter() {
-		if direction == cd.cut_direction {
-			result = cd;
-		}
+/*
+* How often each cut direction should follow the last cut, indexed by cut direction.
+* Comes from the learned model if it has seen this transition, otherwise from the possible transitions of the last cut.
+*/
+fn get_cut_weights(
+	model: &TransitionModel,
+	cut_direction: &CutDirection,
+	note_type: i64,
+	prev_x: usize,
+	prev_y: usize,
+	gap: f64,
+) -> Vec<usize> {
+	if let Some(weights) = model.cut_weights(note_type, gap, cut_direction.cut_direction, prev_x, prev_y) {
+		return weights.to_owned();
+	}
+	let mut weights = vec![0; CUT_DIRECTIONS.len()];
+	for transition in cut_direction.possible_transitions.iter() {
+		weights[*transition as usize] += 1;
+	}
+	weights
+}
+fn get_next_cut(rng: &mut StdRng, cut_weights: &[usize]) -> &'static CutDirection {
+	//get a random cut direction, the more often a cut follows the last one the more likely it is
+	match sample_weighted(rng, cut_weights) {
+		Some(direction) => &CUT_DIRECTIONS[direction],
+		None => &CUT_OMNI,
+	}
+}
+/*
+* Like get_next_cut, but the cut has to swing the other way to the hand's last swing.
+* If the random transition breaks parity another transition that keeps it is picked,
+* and if there isn't one the cut is turned around.
+*/
+fn get_next_cut_with_parity(
+	rng: &mut StdRng,
+	cut_weights: &[usize],
+	note_type: i64,
+	swing: Option<Swing>,
+	stats: &mut GenerationStats,
+) -> &'static CutDirection {
+	let next_cut = get_next_cut(rng, cut_weights);
+	if !breaks_parity(swing, note_type, next_cut.cut_direction) {
+		return next_cut;
+	}
+	stats.parity_repairs += 1;
+	let parity_weights: Vec<usize> = cut_weights
+		.iter()
+		.enumerate()
+		.map(|(transition, weight)| {
+			if breaks_parity(swing, note_type, transition as i64) {
+				0
+			} else {
+				*weight
+			}
+		})
+		.collect();
+	match sample_weighted(rng, &parity_weights) {
+		Some(repaired_cut) => &CUT_DIRECTIONS[repaired_cut],
+		None => &CUT_DIRECTIONS[opposite_cut(next_cut.cut_direction) as usize],
 	}
-//	println!(
-//		"{} to {}",
-//		cut_direction.cut_direction, result.cut_direction
-//	);
-	(result)
 }
 
 fn main() -> std::io::Result<()> {
+	let args: Vec<String> = env::args().skip(1).collect();
+	match args.first().map(|command| command.as_str()) {
+		Some("preview") => {
+			preview::run(&args[1..]);
+			Ok(())
+		}
+		Some("report") => {
+			report::run(&args[1..]);
+			Ok(())
+		}
+		Some("train") => {
+			model::run(&args[1..]);
+			Ok(())
+		}
+		Some("watch") => watch::run(&args[1..]),
+		_ => create_song_zip(&args),
+	}
+}
+
+/*
+* The seed after --seed, or a new random one. It's printed so a good map can be made again.
+*/
+fn get_seed(args: &[String]) -> u64 {
+	let seed = match args.iter().position(|arg| arg == "--seed") {
+		Some(position) => args
+			.get(position + 1)
+			.and_then(|seed| seed.parse().ok())
+			.expect("--seed needs a whole number after it"),
+		None => rand::thread_rng().gen_range(0, u64::from(u32::MAX)),
+	};
+	println!("Seed: {}", seed);
+	seed
+}
+
+fn create_song_zip(args: &[String]) -> std::io::Result<()> {
 	println!("Start map creation");
+	let seed = get_seed(args);
 
-	let map_string: String = create_bsaber_map();
+	let mut processed_notes = Vec::new();
+	let mut stats = GenerationStats::default();
+	let map_string: String = create_bsaber_map(&mut processed_notes, &mut stats, seed)
+		.map_err(|error| std::io::Error::new(std::io::ErrorKind::InvalidData, error))?;
+	write_song_zip(map_string)
+}
+
+/*
+* Zip the map up with the rest of the song into output/song.zip
+*/
+fn write_song_zip(map_string: String) -> std::io::Result<()> {
 	println!("Start zipping");
 	//fs::remove_file("output/song.zip")?;
 	//let output_folder = "/home/matt/Games/beatsaver-viewer-master/song.zip";
+	fs::create_dir_all("output")?;
 	let output_folder = "output/song.zip";
 	let mut file = File::create(output_folder).expect("Couldn't create file");
 	create_zip_archive(&mut file, map_string).expect("Couldn't create archive");
@@ -131,14 +302,34 @@ struct JsonConfig {
 	_previewDuration: f64,
 	_songFilename: String,
 	_coverImageFilename: String,
+	#[serde(default)]
+	_difficultyBeatmapSets: Vec<JsonDifficultyBeatmapSet>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[allow(non_snake_case)]
+struct JsonDifficultyBeatmapSet {
+	_beatmapCharacteristicName: String,
+	_difficultyBeatmaps: Vec<JsonDifficultyBeatmap>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[allow(non_snake_case)]
+struct JsonDifficultyBeatmap {
+	_difficulty: String,
+	_beatmapFilename: String,
+	_noteJumpMovementSpeed: f64,
 }
 
 #[allow(non_snake_case)]
-//0.3947392290249433 110 1182.4482
+//0.3947392290249433 110 1182.4482 followed by the onset strength, energy and 12 chroma values if the peaks were detected with them
 struct Beat {
 	peak_time_sec: f64,
 	id: usize,
 	pitch: f64,
+	onset_strength: Option<f64>,
+	energy: Option<f64>,
+	chroma: Vec<f64>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -147,24 +338,51 @@ struct PatternCollection {
 	normal_patterns: Vec<Pattern>,
 	hard_patterns: Vec<Pattern>,
 }
-#[derive(Serialize, Deserialize)]
+/*
+* Where a placed pattern came from, only library patterns count for the repetition rules
+*/
+#[derive(Clone, Copy, PartialEq, Default)]
+enum PatternSource {
+	#[default]
+	Library,
+	Dynamic,
+	Primitive,
+	Bridge,
+	Drop,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 struct Pattern {
+	//where the pattern came from, e.g. easy/up_down_flourish, set when it's loaded
+	#[serde(skip)]
+	id: String,
+	//the id of the template the pattern was expanded from, None for a pattern written out in full
+	#[serde(skip)]
+	template_id: Option<String>,
+	//what made the pattern, everything read from the pattern files is from the library
+	#[serde(skip)]
+	source: PatternSource,
 	description: String,
 	notes: Vec<PatternNote>,
 	obstacles: Vec<PatternWall>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 struct PatternNote {
 	x: i64,
 	y: i64,
 	cut_direction: i64,
 	note_type: i64,
 	description: String,
+	//offset into the peak list from the start of the pattern
+	#[serde(default)]
 	beat_time_rel: usize,
+	//optional offset in (fractional) beats from the start of the pattern, used instead of beat_time_rel
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	beat_time: Option<f64>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 struct PatternWall {
 	x: i64,
 	y: i64,
@@ -173,181 +391,353 @@ struct PatternWall {
 	width: f64,
 	wall_type: i64,
 	description: String,
+	#[serde(default)]
 	wall_time_rel: usize,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	wall_time: Option<f64>,
 }
 
-struct AddPatternResult {
-	pattern_end_time: f64,
+/*
+* Everything about a map that's being generated, cloned when beam search tries different picks from the same point
+*/
+#[derive(Clone)]
+struct MapState<'a> {
+	rng: StdRng,
 	contents: String,
 	obstacles_content: String,
+	processed_notes: Vec<PatternNote>,
+	//the walls in obstacles_content, timed in beats from the start of the map with their padding taken off
+	processed_walls: Vec<PatternWall>,
+	//where each saber finished its last swing, index 0 is the left saber and 1 the right
+	hand_states: [HandState; 2],
+	pattern_map: HashMap<usize, &'a Pattern>,
+	//the patterns placed on peaks that a later phrase repeats, by peak
+	phrase_patterns: HashMap<usize, Rc<Pattern>>,
+	repetition_tracker: RepetitionTracker,
+	pattern_end_time: f64,
+	stats: GenerationStats,
 }
 
-fn get_config_file() -> JsonConfig {
-	let mut config_file = File::open("src/song/info.dat").unwrap();
-	let mut config_data = String::new();
-	config_file.read_to_string(&mut config_data).unwrap();
-	let config_json: JsonConfig =
-		serde_json::from_str(&config_data[..]).expect("Could not read config file");
-	(config_json)
+impl<'a> MapState<'a> {
+	fn new(rng: StdRng, contents: String) -> MapState<'a> {
+		MapState {
+			rng,
+			contents,
+			obstacles_content: " ".to_owned(),
+			processed_notes: Vec::new(),
+			processed_walls: Vec::new(),
+			hand_states: starting_hand_states(),
+			pattern_map: HashMap::new(),
+			phrase_patterns: HashMap::new(),
+			repetition_tracker: RepetitionTracker::new(),
+			pattern_end_time: 0.0,
+			stats: GenerationStats::default(),
+		}
+	}
 }
 
-fn generate_patterns() -> PatternCollection {
-	let mut easy_patterns = Vec::new();
-	let mut normal_patterns = Vec::new();
-	let mut hard_patterns = Vec::new();
-
-	for entry in glob("src/patterns/easy/*.json").expect("Failed to read glob pattern") {
-		match entry {
-			Ok(path) => {
-				let mut pattern_file = File::open(path).unwrap();
-				let mut pattern_json = String::new();
-				pattern_file.read_to_string(&mut pattern_json).unwrap();
+//where the sabers are before the first note
+fn starting_hand_states() -> [HandState; 2] {
+	[
+		HandState {
+			x: 1,
+			y: 2,
+			cut_direction: CUT_OMNI.cut_direction,
+			swing: None,
+		},
+		HandState {
+			x: 2,
+			y: 2,
+			cut_direction: CUT_OMNI.cut_direction,
+			swing: None,
+		},
+	]
+}
 
-				let pattern_notes: Pattern = serde_json::from_str(&pattern_json[..])
-					.expect("Could not read pattern note json");
-				//println!("pattern:{}",pattern_notes.description.to_owned());
-				easy_patterns.push(pattern_notes);
-			}
-			Err(e) => println!("{:?}", e),
-		}
-	}
-	for entry in glob("src/patterns/normal/*.json").expect("Failed to read glob pattern") {
-		match entry {
-			Ok(path) => {
-				let mut pattern_file = File::open(path).unwrap();
-				let mut pattern_json = String::new();
-				pattern_file.read_to_string(&mut pattern_json).unwrap();
-
-				let pattern_notes: Pattern = serde_json::from_str(&pattern_json[..])
-					.expect("Could not read pattern note json");
-				//println!("pattern:{}",pattern_notes.description.to_owned());
-				normal_patterns.push(pattern_notes);
-			}
-			Err(e) => println!("{:?}", e),
-		}
-	}
-	for entry in glob("src/patterns/hard/*.json").expect("Failed to read glob pattern") {
-		match entry {
-			Ok(path) => {
-				let mut pattern_file = File::open(path).unwrap();
-				let mut pattern_json = String::new();
-				pattern_file.read_to_string(&mut pattern_json).unwrap();
-
-				let pattern_notes: Pattern = serde_json::from_str(&pattern_json[..])
-					.expect("Could not read pattern note json");
-				//println!("pattern:{}",pattern_notes.description.to_owned());
-				hard_patterns.push(pattern_notes);
-			}
-			Err(e) => println!("{:?}", e),
-		}
-	}
-	let pattern_collection: PatternCollection = PatternCollection {
-		easy_patterns,
-		normal_patterns,
-		hard_patterns,
+//where the sabers are after the notes that were placed, for when they aren't all where their patterns put them
+fn hand_states_after_notes(notes: &[PatternNote]) -> [HandState; 2] {
+	let placed_notes = Pattern {
+		id: String::new(),
+		template_id: None,
+		source: PatternSource::Dynamic,
+		description: String::new(),
+		notes: notes.to_vec(),
+		obstacles: Vec::new(),
 	};
+	let mut hand_states = starting_hand_states();
+	advance_hand_states(&mut hand_states, &placed_notes);
+	hand_states
+}
+
+/*
+* What stays the same for the whole map while it's generated
+*/
+struct MapContext<'a> {
+	beats: &'a [Beat],
+	pattern_collection: &'a PatternCollection,
+	lowest_pitch: f64,
+	highest_pitch: f64,
+	lowest_threshold: f64,
+	hard_threshold: f64,
+	normal_threshold: f64,
+	//how intense the music is at each peak, from 0 to 1
+	intensities: &'a [f64],
+	//the notes per second each section should have, if the difficulty has a target
+	density_plan: Option<&'a DensityPlan>,
+	beats_per_minute: f64,
+	vision_window: f64,
+	transition_model: &'a TransitionModel,
+	settings: &'a GeneratorSettings,
+	//for each peak in a phrase that repeats an earlier one, the peak it repeats
+	phrase_sources: &'a [Option<usize>],
+	drops: &'a [Drop],
+	//what the map does around drops at this difficulty, and how long (in seconds) before a drop is left empty
+	drop_response: Option<&'a DropResponse>,
+	thin_out_seconds: f64,
+	crossovers_allowed: bool,
+	//print each placed pattern, beam search places far more than it keeps so it stays quiet
+	log: bool,
+}
 
-	(pattern_collection)
+fn get_config_file() -> Result<JsonConfig, String> {
+	let config_data =
+		fs::read_to_string("src/song/info.dat").map_err(|error| format!("Could not read info.dat: {}", error))?;
+	serde_json::from_str(&config_data[..]).map_err(|error| format!("Could not parse info.dat: {}", error))
 }
+
 /*
-*
+* The note jump speed info.dat gives the map we generate, ExpertPlus.dat
 */
-fn create_bsaber_map() -> String {
-	fs::remove_file("src/song/ExpertPlus.dat").expect("Could not remove song json");
-	let mut file =
-		File::create("src/song/ExpertPlus.dat").expect("Could not create song json file");
+fn get_note_jump_speed(config_json: &JsonConfig) -> f64 {
+	config_json
+		._difficultyBeatmapSets
+		.iter()
+		.flat_map(|beatmap_set| beatmap_set._difficultyBeatmaps.iter())
+		.find(|beatmap| beatmap._beatmapFilename == "ExpertPlus.dat")
+		.map(|beatmap| beatmap._noteJumpMovementSpeed)
+		.unwrap_or(DEFAULT_NOTE_JUMP_SPEED)
+}
 
-	//read info.dat
-	let config_json = get_config_file();
+/*
+* How long (in beats) a note in the middle of the grid hides the notes behind it in this song
+*/
+fn get_vision_window_beats() -> Result<f64, String> {
+	let config_json = get_config_file()?;
+	Ok(vision_window_beats(get_note_jump_speed(&config_json), config_json._beatsPerMinute))
+}
 
-	let pattern_collection = generate_patterns();
+/*
+* Read a pattern json file, templates are expanded into all the patterns they describe
+*/
+fn read_pattern_file(path: &Path) -> Result<Vec<Pattern>, String> {
+	let pattern_json = fs::read_to_string(path)
+		.map_err(|error| format!("Could not read pattern file {}: {}", path.display(), error))?;
+
+	//patterns are known by their path inside src/patterns without the .json
+	let pattern_id = path
+		.strip_prefix("src/patterns")
+		.unwrap_or(path)
+		.with_extension("")
+		.display()
+		.to_string();
+	parse_pattern_json(&pattern_json, &pattern_id)
+}
 
-	let version = "2.0.0";
-	//get configs from info.dat
-	let beats_per_minute: f64 = config_json._beatsPerMinute;
-	//let duration_seconds: f64 = config_json._duration_seconds;
-	//let song_duration_beats: f64 = (duration_seconds / 60.0) * beats_per_minute;
+fn parse_pattern_json(pattern_json: &str, pattern_id: &str) -> Result<Vec<Pattern>, String> {
+	let pattern_error = |error: serde_json::Error| format!("Could not read pattern {}: {}", pattern_id, error);
+	let pattern_value: serde_json::Value = serde_json::from_str(pattern_json).map_err(pattern_error)?;
+	if is_template(&pattern_value) {
+		let template: PatternTemplate = serde_json::from_value(pattern_value).map_err(pattern_error)?;
+		expand_template(&template, pattern_id)
+	} else {
+		let mut pattern: Pattern = serde_json::from_value(pattern_value).map_err(pattern_error)?;
+		pattern.id = pattern_id.to_owned();
+		Ok(vec![pattern])
+	}
+}
 
-	//start of ExperPlus.json
-	let mut contents: String = format!(
-		"{{\"_version\":\"{}\",
-			\"_BPMChanges\":[],
-			\"_events\":[{{
-				\"_time\":3.199899911880493,
-				\"_type\":4,
-				\"_value\":3
-			}}],
-			\"_notes\":[",
-		version
-	)
-	.to_owned();
+/*
+* One value from a line of peak_times.txt
+*/
+fn parse_peak_value<T: std::str::FromStr>(value: Option<&str>, line_number: usize, name: &str) -> Result<T, String> {
+	value
+		.and_then(|value| value.parse::<T>().ok())
+		.ok_or_else(|| format!("Line {} of peak_times.txt has no {} that can be read", line_number, name))
+}
 
-	//read the peak times and pitches
+/*
+* Read the peak times, ids, pitches and what else peaks-detection.py measured from peak_times.txt.
+* Every line has one of the PEAK_COLUMNS counts, anything else is from an older script and is an error.
+* A peak closer than MIN_BEAT_SPACING_TIME to the one before it is left out.
+*/
+fn read_peak_times() -> Result<Vec<Beat>, String> {
 	let peak_times_file =
-		File::open("src/song/peak_times.txt").expect("Could not read peak times file");
+		File::open("src/song/peak_times.txt").map_err(|error| format!("Could not read peak times file: {}", error))?;
 	let peak_times_buffer = BufReader::new(&peak_times_file);
 	//we'll keep track of times for the peaks and where walls might go
 	let mut beats = Vec::new();
-
-	//find the highest and highest pitch while we're at it
-	let mut highest_pitch: f64 = 0.0;
-	let mut lowest_pitch: f64 = 1600.0;
 	let mut prev_peak_time: f64 = 0.0;
 	//iterate over the file line by line
-	for line in peak_times_buffer.lines() {
-		let line = line.unwrap();
+	for (line_id, line) in peak_times_buffer.lines().enumerate() {
+		let line = line.map_err(|error| format!("Could not read peak times file: {}", error))?;
+		let line_number = line_id + 1;
+		let column_count = line.split_whitespace().count();
+		if !PEAK_COLUMNS.contains(&column_count) {
+			return Err(format!(
+				"Line {} of peak_times.txt has {} values, expected 3, 5 or 17. Run peaks-detection.py again to write it in the current format",
+				line_number, column_count
+			));
+		}
 		let mut iter = line.split_whitespace();
 		//1.0216780045351475 175.67802
 		//first number is the time (in seconds), second number is the pitch measurement
-		let peak_time = iter.next().unwrap();
-		let peak_id = iter.next().unwrap();
-		let peak_pitch = iter.next().unwrap();
-		//convert to floats
-		let peak_time_as_float = peak_time.parse::<f64>().unwrap();
-		let peak_id_as_usize = peak_id.parse::<usize>().unwrap();
-		let peak_pitch_as_float = peak_pitch.parse::<f64>().unwrap();
+		let peak_time_as_float: f64 = parse_peak_value(iter.next(), line_number, "peak time")?;
+		let peak_id_as_usize: usize = parse_peak_value(iter.next(), line_number, "peak id")?;
+		let peak_pitch_as_float: f64 = parse_peak_value(iter.next(), line_number, "pitch")?;
+		let extra = iter
+			.map(|value| parse_peak_value(Some(value), line_number, "onset, energy or chroma value"))
+			.collect::<Result<Vec<f64>, String>>()?;
 		if peak_time_as_float - prev_peak_time >= MIN_BEAT_SPACING_TIME {
 			let beat = Beat {
 				peak_time_sec: peak_time_as_float,
 				id: peak_id_as_usize,
 				pitch: peak_pitch_as_float,
+				onset_strength: extra.first().cloned(),
+				energy: extra.get(1).cloned(),
+				chroma: extra.iter().skip(2).cloned().collect(),
 			};
 			//store in vector
 			beats.push(beat);
-			//track highest pitch
-			if peak_pitch_as_float > highest_pitch {
-				highest_pitch = peak_pitch_as_float;
-			}
-			if peak_pitch_as_float < lowest_pitch {
-				lowest_pitch = peak_pitch_as_float;
-			}
 		}
 		prev_peak_time = peak_time_as_float;
 	}
+	Ok(beats)
+}
+
+/*
+* Generate the map and write it out to src/song/ExpertPlus.dat
+*/
+fn create_bsaber_map(
+	processed_notes: &mut Vec<PatternNote>,
+	stats: &mut GenerationStats,
+	seed: u64,
+) -> Result<String, String> {
+	let contents = build_bsaber_map(processed_notes, stats, seed, true)?;
+
+	fs::remove_file("src/song/ExpertPlus.dat").expect("Could not remove song json");
+	let mut file =
+		File::create("src/song/ExpertPlus.dat").expect("Could not create song json file");
+	//write it all out to file
+	file.write_all(contents.as_bytes())
+		.expect("Could not write song json to file");
+	drop(file);
+	Ok(contents)
+}
+
+/*
+* Generate the map json from info.dat, the pattern library and the peak times, or say which of them can't be read.
+* Every random choice comes from the seed, so the same inputs and seed make the same map.
+* write_history writes the fitness history of a refined map to the file in the refine settings.
+*/
+fn build_bsaber_map(
+	processed_notes: &mut Vec<PatternNote>,
+	stats: &mut GenerationStats,
+	seed: u64,
+	write_history: bool,
+) -> Result<String, String> {
+	//read info.dat
+	let config_json = get_config_file()?;
+	let settings = get_generator_settings()?;
+
+	let pattern_collection = load_library(&settings.library)?;
+	let transition_model = load_transition_model(&settings.transitions)?;
+
+	let version = "2.0.0";
+	//get configs from info.dat
+	let beats_per_minute: f64 = config_json._beatsPerMinute;
+	let vision_window = vision_window_beats(get_note_jump_speed(&config_json), beats_per_minute);
+	//let duration_seconds: f64 = config_json._duration_seconds;
+	//let song_duration_beats: f64 = (duration_seconds / 60.0) * beats_per_minute;
+
+	//read the peak times and pitches
+	let beats = read_peak_times()?;
+	if beats.is_empty() {
+		return Err("peak_times.txt has no peaks".to_owned());
+	}
+
+	//find the highest and highest pitch while we're at it
+	let mut highest_pitch: f64 = 0.0;
+	let mut lowest_pitch: f64 = 1600.0;
+	for beat in &beats {
+		//track highest pitch
+		if beat.pitch > highest_pitch {
+			highest_pitch = beat.pitch;
+		}
+		if beat.pitch < lowest_pitch {
+			lowest_pitch = beat.pitch;
+		}
+	}
+
+	//drops get their lighting hits up front, with the rest of the events
+	let drops = find_drops(&beats, &settings.drops);
+	let lighting_hits = match settings.drop_response() {
+		Some(response) if response.lighting_hit => lighting_hit_events(&drops, beats_per_minute),
+		_ => String::new(),
+	};
+
+	//start of ExperPlus.json, the seed is kept so the map can be made again
+	let contents: String = format!(
+		"{{\"_version\":\"{}\",
+			\"_customData\":{{\"_seed\":{}}},
+			\"_BPMChanges\":[],
+			\"_events\":[{{
+				\"_time\":3.199899911880493,
+				\"_type\":4,
+				\"_value\":3
+			}}{}],
+			\"_notes\":[",
+		version, seed, lighting_hits
+	)
+	.to_owned();
 
-	let mut processed_notes = Vec::new();
 	//generate the map
-	contents = generate_map(
+	let rng = StdRng::seed_from_u64(seed);
+	generate_map(
+		rng,
 		contents,
 		&beats,
+		&drops,
 		&pattern_collection,
 		highest_pitch,
 		lowest_pitch,
 		beats_per_minute,
-		&mut processed_notes,
-	);
-	//write it all out to file
-	file.write_all(contents.as_bytes())
-		.expect("Could not write song json to file");
-	drop(file);
-	(contents)
+		vision_window,
+		&transition_model,
+		processed_notes,
+		&settings,
+		stats,
+		write_history,
+	)
 }
-fn get_next_x(note_type:usize, direction: &CutDirection, prev_x: usize) -> usize {
+fn get_next_x(
+	rng: &mut StdRng,
+	model: &TransitionModel,
+	note_type: usize,
+	direction: &CutDirection,
+	prev_x: usize,
+	gap: f64,
+) -> usize {
+	if let Some(weights) = model.column_weights(note_type as i64, gap, direction.cut_direction, prev_x) {
+		//the model only knows the 4x3 grid, anything else falls through to the table
+		if let Some(x) = sample_weighted(rng, weights).filter(|x| *x < 4) {
+			return x;
+		}
+	}
 	let mut x_dir: i64 = direction.x_dir;
 	if x_dir == 0 {
-		x_dir = rand::thread_rng().gen_range(-1, 1);
+		x_dir = rng.gen_range(-1, 1);
 	}
-	let random_change: i64 = x_dir * rand::thread_rng().gen_range(1, 3);
+	let random_change: i64 = x_dir * rng.gen_range(1, 3);
 
 	let mut random_x: i64 = (prev_x as i64) + random_change;
 	if note_type == 0 && random_x > 1 {
@@ -355,417 +745,530 @@ fn get_next_x(note_type:usize, direction: &CutDirection, prev_x: usize) -> usize
 	}else if note_type == 1 && random_x < 2 {
 		random_x = 2;
 	}
-	if random_x < 0 {
-		random_x = 0;
-	} else if random_x > 3 {
-		random_x = 3;
-	}
-
-	(random_x as usize)
+	random_x.clamp(0, 3) as usize
 }
-fn get_next_y(direction: &CutDirection, prev_y: usize) -> usize {
+fn get_next_y(
+	rng: &mut StdRng,
+	model: &TransitionModel,
+	note_type: usize,
+	direction: &CutDirection,
+	prev_y: usize,
+	gap: f64,
+) -> usize {
+	if let Some(weights) = model.layer_weights(note_type as i64, gap, direction.cut_direction, prev_y) {
+		if let Some(y) = sample_weighted(rng, weights).filter(|y| *y < 3) {
+			return y;
+		}
+	}
 	let mut y_dir: i64 = direction.y_dir;
 	if y_dir == 0 {
-		y_dir = rand::thread_rng().gen_range(-1, 1);
+		y_dir = rng.gen_range(-1, 1);
 	}
-	let random_change: i64 = y_dir * rand::thread_rng().gen_range(1, 3);
+	let random_change: i64 = y_dir * rng.gen_range(1, 3);
 
-	let mut random_y: i64 = (prev_y as i64) + random_change;
-	if random_y < 0 {
-		random_y = 0;
-	} else if random_y > 2 {
-		random_y = 2;
+	let random_y: i64 = (prev_y as i64) + random_change;
+
+	random_y.clamp(0, 2) as usize
+}
+
+/*
+* Pick the next cut and position for one saber of a dynamic pattern.
+* A pick that makes an uncomfortable swing from the saber's last note is made again,
+* keeping the last one if they all are.
+*/
+fn get_next_dynamic_note(
+	rng: &mut StdRng,
+	model: &TransitionModel,
+	hand_state: &HandState,
+	note_type: usize,
+	gap: f64,
+	stats: &mut GenerationStats,
+) -> (&'static CutDirection, usize, usize) {
+	let prev_cut_direction = &CUT_DIRECTIONS[hand_state.cut_direction as usize];
+	let (prev_x, prev_y) = (hand_state.x as usize, hand_state.y as usize);
+	let cut_weights = get_cut_weights(model, prev_cut_direction, note_type as i64, prev_x, prev_y, gap);
+	let prev_note = dynamic_note(prev_x, prev_y, prev_cut_direction, note_type);
+	let mut attempt = 1;
+	loop {
+		let direction = get_next_cut_with_parity(rng, &cut_weights, note_type as i64, hand_state.swing, stats);
+		let requested_x = get_next_x(rng, model, note_type, direction, prev_x, gap);
+		let requested_y = get_next_y(rng, model, note_type, direction, prev_y, gap);
+
+		let transition = swing_transition(&prev_note, &dynamic_note(requested_x, requested_y, direction, note_type));
+		if attempt == MAX_SWING_ATTEMPTS || !is_uncomfortable(&transition, gap) {
+			return (direction, requested_x, requested_y);
+		}
+		stats.uncomfortable_swings += 1;
+		attempt += 1;
 	}
+}
 
-	(random_y as usize)
+fn dynamic_note(x: usize, y: usize, direction: &CutDirection, note_type: usize) -> PatternNote {
+	PatternNote {
+		x: x as i64,
+		y: y as i64,
+		cut_direction: direction.cut_direction,
+		note_type: note_type as i64,
+		description: format!("{},{}", x, y),
+		beat_time_rel: 0,
+		beat_time: None,
+	}
 }
 
-#[allow(clippy::cyclomatic_complexity)]
+/*
+* Alternating notes for both sabers on the next few peaks.
+* Each note is picked from the transitions, then the placement solver moves it to the nearest legal cell and cut,
+* notes that can't be placed anywhere are left out.
+*/
+#[allow(clippy::too_many_arguments)]
 fn generate_dynamic_pattern(
-	left_prev_cut_direction: &CutDirection,
-	left_prev_x: usize,
-	left_prev_y: usize,
-	right_prev_cut_direction: &CutDirection,
-	right_prev_x: usize,
-	right_prev_y: usize,
+	rng: &mut StdRng,
+	hand_states: &[HandState; 2],
+	model: &TransitionModel,
+	step_gaps: &[f64],
+	steps: (usize, usize),
+	pitch_hints: Option<&[PitchHint]>,
+	vision_window: f64,
+	stats: &mut GenerationStats,
 ) -> Pattern {
-	//placeholder logic
 	let mut notes: Vec<PatternNote> = Vec::new();
-	let mut obstacles = Vec::new();
+	let obstacles = Vec::new();
 	let description = "Dynamic pattern".to_owned();
 
-	//let center = rand::thread_rng().gen_range(1, 3); //X=1 or 2
-
-	let number_of_notes = rand::thread_rng().gen_range(4, 9);
+	let number_of_notes = rng.gen_range(steps.0, steps.1 + 1);
+
+	let mut hand_states = *hand_states;
+	for (time_rel, gap) in step_gaps.iter().cloned().enumerate().take(number_of_notes) {
+		//the last step is followed by whatever pattern goes on the next peak
+		let next_gap = step_gaps.get(time_rel + 1).cloned();
+		//left saber first, the right saber is placed around it
+		let mut step_notes: Vec<PatternNote> = Vec::new();
+		for (note_type, hand_state) in hand_states.iter_mut().enumerate() {
+			let (direction, requested_x, requested_y) =
+				get_next_dynamic_note(rng, model, hand_state, note_type, gap, stats);
+			let mut request = PatternNote {
+				beat_time_rel: time_rel,
+				..dynamic_note(requested_x, requested_y, direction, note_type)
+			};
+			//the pitch of the peak picks the layer, and the cut if the melody moved and it keeps parity
+			if let Some(hint) = pitch_hints.and_then(|pitch_hints| pitch_hints.get(time_rel)) {
+				request.y = hint.layer;
+				request.description = format!("{},{}", request.x, request.y);
+				stats.pitch_layers += 1;
+				if let Some(cut_direction) = hint.cut_direction {
+					if !breaks_parity(hand_state.swing, note_type as i64, cut_direction) {
+						request.cut_direction = cut_direction;
+						stats.pitch_cuts += 1;
+					}
+				}
+			}
 
-	//LEFT
+			let constraints = PlacementConstraints {
+				hand_state,
+				other_note: step_notes.first(),
+				gap,
+				next_gap,
+				vision_window,
+			};
+			match solve_placement(&request, &constraints) {
+				Some(note) => {
+					if note.x != request.x || note.y != request.y || note.cut_direction != request.cut_direction {
+						stats.placement_moves += 1;
+					}
+					*hand_state = hand_state_after(hand_state, &note);
+					step_notes.push(note);
+				}
+				None => stats.unplaceable_notes += 1,
+			}
+		}
+		notes.extend(step_notes);
+	}
 
-	let mut l_prev_cut_direction = left_prev_cut_direction;
-	let mut l_prev_x = left_prev_x;
-	let mut l_prev_y = left_prev_y;
-	
-	
-	let mut r_prev_cut_direction = right_prev_cut_direction;
-	let mut r_prev_x = right_prev_x;
-	let mut r_prev_y = right_prev_y;
+	//bombs are kept clear of the swings by beat, each step lands on the peak after the gap before it
+	let step_times: Vec<f64> = step_gaps
+		.iter()
+		.scan(0.0, |time, gap| {
+			*time += gap;
+			Some(*time)
+		})
+		.collect();
+	add_dynamic_bombs(rng, &mut notes, &step_times);
 
-	let mut left_time_rel = 0;
-	let mut right_time_rel = 0;
-	for i in 0..number_of_notes {
-		//let direction: CutDirection = get_cut_direction(requested_x, requested_y, prev_cut_direction);
-		let direction: &CutDirection = get_next_cut(l_prev_cut_direction);
+	let pattern: Pattern = Pattern {
+		id: "dynamic".to_owned(),
+		template_id: None,
+		source: PatternSource::Dynamic,
+		description,
+		notes,
+		obstacles,
+	};
 
-		let requested_x = get_next_x(0, direction, l_prev_x);
-		let requested_y = get_next_y(direction, l_prev_y);
+	pattern
+}
 
-		let description = format!("{},{}", requested_x, requested_y).to_owned();
+/*
+* Time (in beats) from the peak before to the given peak, the last peak is used past the end of the song
+*/
+fn peak_gap_in_beats(beats: &[Beat], peak_id: usize, beats_per_minute: f64) -> f64 {
+	let peak_id = peak_id.min(beats.len() - 1);
+	if peak_id == 0 {
+		return 0.0;
+	}
+	((beats[peak_id].peak_time_sec - beats[peak_id - 1].peak_time_sec) / 60.0) * beats_per_minute
+}
 
-		let note = PatternNote {
-			x: requested_x as i64,
-			y: requested_y as i64,
-			cut_direction: direction.cut_direction,
-			note_type: 0,
-			description,
-			beat_time_rel: left_time_rel,
-		};
+fn snap_to_beat_grid(time_in_beats: f64) -> f64 {
+	(time_in_beats * PATTERN_GRID_DIVISION).round() / PATTERN_GRID_DIVISION
+}
 
-		notes.push(note);
-		l_prev_x = requested_x;
-		l_prev_y = requested_y;
-		l_prev_cut_direction = direction;
-		left_time_rel += 1;
+/*
+* Where (in beats) a pattern placed on a peak starts, the peak snapped to the beat grid.
+* Notes and walls timed in fractional beats count from here, past the end of the song it's the last peak.
+*/
+fn pattern_anchor_in_beats(beats: &[Beat], beat_id: usize, beats_per_minute: f64) -> f64 {
+	let beat_id = beat_id.min(beats.len() - 1);
+	snap_to_beat_grid((beats[beat_id].peak_time_sec / 60.0) * beats_per_minute)
+}
 
-		//RIGHT
+/*
+* When a note of a pattern placed on a peak is cut, in beats and in seconds.
+* Notes timed in fractional beats count from the pattern's anchor, the others land on the peak beat_time_rel after it.
+*/
+fn pattern_note_time(beats: &[Beat], beat_id: usize, note: &PatternNote, beats_per_minute: f64) -> (f64, f64) {
+	match note.beat_time {
+		Some(beat_time) => {
+			let note_time_in_beats = pattern_anchor_in_beats(beats, beat_id, beats_per_minute) + beat_time;
+			(note_time_in_beats, (note_time_in_beats / beats_per_minute) * 60.0)
+		}
+		None => {
+			let beat_next_id = (beat_id + note.beat_time_rel + 1).min(beats.len() - 1);
+			let peak_time_sec = beats[beat_next_id].peak_time_sec;
+			((peak_time_sec / 60.0) * beats_per_minute, peak_time_sec)
+		}
+	}
+}
 
-		//let direction: CutDirection = get_cut_direction(requested_x, requested_y, prev_cut_direction);
-		let direction: &CutDirection = get_next_cut(r_prev_cut_direction);
+fn add_pattern(state: &mut MapState, context: &MapContext, pattern: &Pattern, beat_id: usize) {
+	let beats = context.beats;
+	let beats_per_minute = context.beats_per_minute;
+	if context.log {
+		println!("pattern:{}", pattern.description.to_owned());
+	}
+	let mut last_note_time_in_beats = 0.0;
 
-		let requested_x = get_next_x(1, direction, r_prev_x);
-		let requested_y = get_next_y(direction, r_prev_y);
+	let pattern_start_in_beats = pattern_anchor_in_beats(beats, beat_id, beats_per_minute);
 
-		let description = format!("{},{}", requested_x, requested_y).to_owned();
+	let mut note_type = state.rng.gen_range(0, 1);
+	if context.settings.pitch.hand_by_register && pattern.notes.iter().any(|note| note.note_type < 0) {
+		let position = pitch_position(beats[beat_id].pitch, context.lowest_pitch, context.highest_pitch);
+		note_type = hand_for_position(&context.settings.pitch, position);
+		state.stats.pitch_hands += 1;
+	}
+	//work out where every note lands before adding any, so bombs can be checked against the swings around them
+	let mut placed_notes: Vec<(PatternNote, f64)> = Vec::new();
+	for pattern_note in &pattern.notes {
+		let (note_time_in_beats, note_time_sec) = pattern_note_time(beats, beat_id, pattern_note, beats_per_minute);
 
-		let note = PatternNote {
-			x: requested_x as i64,
-			y: requested_y as i64,
-			cut_direction: direction.cut_direction,
-			note_type: 1,
-			description,
-			beat_time_rel: right_time_rel,
+		let mut pattern_note_type = pattern_note.note_type;
+		if pattern_note_type < 0 {
+			pattern_note_type = note_type;
+		}
+		//processed notes keep their time in beats from the start of the map
+		let placed_note = PatternNote {
+			x: pattern_note.x,
+			y: pattern_note.y,
+			cut_direction: pattern_note.cut_direction,
+			note_type: pattern_note_type,
+			description: pattern_note.description.to_owned(),
+			beat_time_rel: pattern_note.beat_time_rel,
+			beat_time: Some(note_time_in_beats),
 		};
+		placed_notes.push((placed_note, note_time_sec));
+	}
 
-		notes.push(note);
-		r_prev_x = requested_x;
-		r_prev_y = requested_y;
-		r_prev_cut_direction = direction;
-		right_time_rel += 1;
-	}
-
-	//compatibility checks
-	let mut l_x = -1;
-	let mut l_y = -1;
-	let mut l_d = 8;
-	let mut l_t = 0;
-	let mut r_x = -1;
-	let mut r_y = -1;
-	let mut r_d = 8;
-	let mut r_t = 0;
-	let mut compatible_notes : Vec<PatternNote> = Vec::new();
-	for note in &mut notes {
-		if note.note_type == 0 {
-			l_x = note.x;
-			l_y = note.y;
-			l_d = note.cut_direction;
-			l_t = note.beat_time_rel;
-		} else if note.note_type == 1 {
-			r_x = note.x;
-			r_y = note.y;
-			r_d = note.cut_direction;
-			r_t = note.beat_time_rel;
-		}
-		let mut occupy_same_coords = l_x == r_x && l_y == r_y && l_t == r_t;
-		while occupy_same_coords {
-			println!("Conflict at {},{} vs {},{}",l_x,l_y,r_x,r_y);
-			let cut_direction: &CutDirection = &CUT_DIRECTIONS[r_d as usize];
-			let mut x_dir: i64 = cut_direction.x_dir;
-			if x_dir == 0 {
-				x_dir = rand::thread_rng().gen_range(-1, 1);
-			}
-			let random_x_change: i64 = x_dir * rand::thread_rng().gen_range(1, 3);
-			let mut new_x = r_x + random_x_change;
-			
-			let mut y_dir: i64 = cut_direction.y_dir;
-			if y_dir == 0 {
-				y_dir = rand::thread_rng().gen_range(-1, 1);
-			}
-			let random_y_change: i64 = y_dir * rand::thread_rng().gen_range(1, 3);
-			let mut new_y = r_y + random_y_change;
-			//if they go off the edge then have them wrap around
-			if new_x < 0 {
-				new_x = 3;
-			} else if new_x > 3 {
-				new_x = 0;
+	//the sabers are worked out again from the notes that were placed if any were left out or moved
+	let mut notes_changed = false;
+	//a pattern that would run into the thin out before a drop stops where the thin out starts
+	if let Some(cut_time) = context
+		.drop_response
+		.and_then(|_| thin_out_cut(context.drops, context.thin_out_seconds, beats[beat_id].peak_time_sec))
+	{
+		let note_count = placed_notes.len();
+		placed_notes.retain(|(_, note_time_sec)| *note_time_sec < cut_time);
+		state.stats.drop_cut_notes += note_count - placed_notes.len();
+		notes_changed |= placed_notes.len() < note_count;
+	}
+
+	//once a saber loses or gains a note, its later notes may have to turn around to keep alternating
+	let mut changed_hands = [false; 2];
+
+	//notes in the middle of the grid that hide the notes coming up behind them are moved out of the way or dropped
+	let pattern_notes: Vec<PatternNote> = placed_notes.iter().map(|(note, _)| note.clone()).collect();
+	let recent_vision_notes: Vec<&PatternNote> = state
+		.processed_notes
+		.iter()
+		.filter(|note| note_order(note) >= pattern_start_in_beats - context.vision_window)
+		.collect();
+	let mut placed_notes: Vec<(PatternNote, f64)> = placed_notes
+		.into_iter()
+		.filter_map(|(mut placed_note, note_time_sec)| {
+			match fix_vision_block(&placed_note, &pattern_notes, &recent_vision_notes, context.vision_window) {
+				VisionFix::Keep => {}
+				VisionFix::MoveTo(layer) => {
+					if context.log {
+						println!(
+							"Note at {},{} moved to layer {} so it doesn't block vision",
+							placed_note.x, placed_note.y, layer
+						);
+					}
+					placed_note.y = layer;
+				}
+				VisionFix::Drop => {
+					if context.log {
+						println!(
+							"Note at {},{} removed so it doesn't block vision",
+							placed_note.x, placed_note.y
+						);
+					}
+					if let Some(changed_hand) = changed_hands.get_mut(placed_note.note_type as usize) {
+						*changed_hand = true;
+					}
+					return None;
+				}
+				VisionFix::Hidden => {
+					if context.log {
+						println!(
+							"Note at {},{} removed, the notes before it hide it",
+							placed_note.x, placed_note.y
+						);
+					}
+					if let Some(changed_hand) = changed_hands.get_mut(placed_note.note_type as usize) {
+						*changed_hand = true;
+					}
+					return None;
+				}
 			}
-			if new_y < 0 {
-				new_y = 2;
-			} else if new_y > 2 {
-				new_y = 0;
+			Some((placed_note, note_time_sec))
+		})
+		.collect();
+	notes_changed |= placed_notes.len() < pattern_notes.len();
+
+	//notes that would make a saber swing faster than the difficulty allows go to the other saber, or are left out
+	if let Some(limit) = context.settings.swing_limit() {
+		let window_seconds = context.settings.swing_limits.window_seconds;
+		let lookback_beats = (window_seconds.max(limit.min_gap_seconds) / 60.0) * beats_per_minute;
+		let recent_notes: Vec<&PatternNote> = state
+			.processed_notes
+			.iter()
+			.filter(|note| note_order(note) >= pattern_start_in_beats - lookback_beats)
+			.collect();
+		placed_notes.sort_by(|a, b| note_order(&a.0).partial_cmp(&note_order(&b.0)).unwrap());
+		let rate_pattern_notes: Vec<PatternNote> = placed_notes.iter().map(|(note, _)| note.clone()).collect();
+		let mut checked_notes: Vec<(PatternNote, f64)> = Vec::new();
+		for (placed_note, note_time_sec) in placed_notes {
+			let around: Vec<&PatternNote> = recent_notes
+				.iter()
+				.cloned()
+				.chain(checked_notes.iter().map(|(note, _)| note))
+				.collect();
+			match fix_swing_rate(&around, &placed_note, limit, window_seconds, beats_per_minute) {
+				RateFix::Keep => checked_notes.push((placed_note, note_time_sec)),
+				RateFix::MoveTo(mut moved_note) => {
+					notes_changed = true;
+					//the other side of the grid can put the note in front of others, or on a cell the pattern uses later
+					let notes_before: Vec<&PatternNote> = recent_vision_notes
+						.iter()
+						.cloned()
+						.chain(checked_notes.iter().map(|(note, _)| note))
+						.collect();
+					let in_view =
+						match fix_vision_block(&moved_note, &rate_pattern_notes, &notes_before, context.vision_window) {
+							VisionFix::Keep => true,
+							VisionFix::MoveTo(layer) => {
+								moved_note.y = layer;
+								true
+							}
+							VisionFix::Drop | VisionFix::Hidden => false,
+						};
+					let notes_around: Vec<&PatternNote> = around.iter().cloned().chain(rate_pattern_notes.iter()).collect();
+					if in_view && !is_occupied(&notes_around, &moved_note) {
+						state.stats.swing_rate_moves += 1;
+						changed_hands[moved_note.note_type as usize] = true;
+						checked_notes.push((moved_note, note_time_sec));
+					} else {
+						state.stats.swing_rate_drops += 1;
+						changed_hands[placed_note.note_type as usize] = true;
+					}
+				}
+				RateFix::Drop => {
+					state.stats.swing_rate_drops += 1;
+					notes_changed = true;
+					changed_hands[placed_note.note_type as usize] = true;
+				}
 			}
-			println!("Conflict resolved right saber to {},{} -> {},{}",r_x,r_y,new_x,new_y);
-			r_x = new_x;
-			r_y = new_y;
-			note.x = new_x;
-			note.y = new_y;
-			
-			
-			occupy_same_coords = l_x == r_x && l_y == r_y && l_t == r_t;
 		}
-		if note.note_type == 0 {
-			
-			//println!("Left Note at {},{} d={} t={}",l_x,l_y,l_d,l_t);
-			let new_note = PatternNote {
-				x: l_x,
-				y: l_y,
-				cut_direction: l_d,
-				note_type: 0,
-				description:note.description.to_owned(),
-				beat_time_rel:l_t,
-			};
-	
-			compatible_notes.push(new_note);
-			
-		} else if note.note_type == 1 {
-			//println!("Right Note at {},{} d={} t={}",r_x,r_y,r_d,r_t);
-			let new_note = PatternNote {
-				x: r_x,
-				y: r_y,
-				cut_direction: r_d,
-				note_type: 1,
-				description:note.description.to_owned(),
-				beat_time_rel:r_t,
-			};
-	
-			compatible_notes.push(new_note);
-			l_x = -1;
-			l_y = -1;
-			l_d = 8;
-			l_t = 0;
-			r_x = -1;
-			r_y = -1;
-			r_d = 8;
-			r_t = 0;
-		}
-			
-
+		placed_notes = checked_notes;
 	}
 
-	let pattern: Pattern = Pattern {
-		description,
-		notes:compatible_notes,
-		obstacles,
-	};
-
-	(pattern)
-}
-
-#[allow(clippy::too_many_arguments)]
-fn add_pattern(
-	pattern: &Pattern,
-	mut contents: String,
-	beats: &[Beat],
-	processed_notes: &mut Vec<PatternNote>,
-	beat_id: usize,
-	beats_per_minute: f64,
-	mut pattern_end_time: f64,
-	mut obstacles_content: String,
-) -> AddPatternResult {
-	println!("pattern:{}", pattern.description.to_owned());
-	let mut last_note_time_in_beats = 0.0;
+	//then every note left on a saber that lost or gained one is turned around where it would swing the same way twice
+	if changed_hands.contains(&true) {
+		placed_notes.sort_by(|a, b| note_order(&a.0).partial_cmp(&note_order(&b.0)).unwrap());
+	}
+	for (hand, _) in changed_hands.iter().enumerate().filter(|(_, changed_hand)| **changed_hand) {
+		let turns = turn_for_parity(
+			&state.processed_notes,
+			hand as i64,
+			placed_notes.iter_mut().map(|(note, _)| note),
+		);
+		state.stats.swing_rate_turns += turns;
+		notes_changed |= turns > 0;
+	}
 
-	let note_type = rand::thread_rng().gen_range(0, 1);
-	for pattern_note in &pattern.notes {
-		let mut beat_next_id = beat_id + pattern_note.beat_time_rel + 1;
-		if beat_next_id >= beats.len() {
-			beat_next_id = beats.len() - 1;
+	for (placed_note, note_time_sec) in &placed_notes {
+		if placed_note.note_type == NOTE_TYPE_BOMB {
+			let swinging_notes: Vec<PatternNote> = state
+				.processed_notes
+				.iter()
+				.chain(placed_notes.iter().map(|(note, _)| note))
+				.filter(|note| note.note_type != NOTE_TYPE_BOMB)
+				.cloned()
+				.collect();
+			if !bomb_is_safe(placed_note, note_order(placed_note), &map_note_times(&swinging_notes)) {
+				if context.log {
+					println!(
+						"Bomb at {},{} removed from the swing path",
+						placed_note.x, placed_note.y
+					);
+				}
+				continue;
+			}
 		}
-		let note_time_in_beats = (beats[beat_next_id].peak_time_sec / 60.0) * beats_per_minute;
+		let note_time_in_beats = placed_note.beat_time.unwrap_or(0.0);
 		last_note_time_in_beats = note_time_in_beats;
-		let pattern_x = pattern_note.x;
-		let pattern_y = pattern_note.y;
 
-		let mut pattern_note_type = pattern_note.note_type;
-		if pattern_note_type < 0 {
-			pattern_note_type = note_type;
-		}
-		let pattern_cut_direction = pattern_note.cut_direction;
-
-		pattern_end_time = beats[beat_next_id].peak_time_sec;
+		state.pattern_end_time = state.pattern_end_time.max(*note_time_sec);
 		//create the note using the values we calculated
 		let note: String = create_note_json(
 			beat_id,
-			pattern_end_time,
+			*note_time_sec,
 			1600.0,
 			note_time_in_beats,
-			pattern_x,
-			pattern_y,
-			pattern_note_type,
-			pattern_cut_direction,
-			pattern_note.description.to_owned(),
+			placed_note.x,
+			placed_note.y,
+			placed_note.note_type,
+			placed_note.cut_direction,
+			placed_note.description.to_owned(),
 		);
 		//add the note pattern to the json string
-		contents.push_str(&note);
-		let processed_note = PatternNote {
-			x: pattern_note.x,
-			y: pattern_note.y,
-			cut_direction: pattern_note.cut_direction,
-			note_type: pattern_note.note_type,
-			description: "Test".to_owned(),
-			beat_time_rel: pattern_note.beat_time_rel,
-		};
-		processed_notes.push(processed_note);
+		state.contents.push_str(&note);
+		state.processed_notes.push(placed_note.clone());
 	}
 
 	for wall in &pattern.obstacles {
-		let mut beat_next_id = beat_id + wall.wall_time_rel;
-		if beat_next_id >= beats.len() {
-			beat_next_id = beats.len() - 1;
-		}
-		let wall_time_in_beats = (beats[beat_next_id].peak_time_sec / 60.0) * beats_per_minute;
+		let wall_time_in_beats = match wall.wall_time {
+			Some(wall_time) => pattern_start_in_beats + wall_time,
+			None => {
+				let mut beat_next_id = beat_id + wall.wall_time_rel;
+				if beat_next_id >= beats.len() {
+					beat_next_id = beats.len() - 1;
+				}
+				(beats[beat_next_id].peak_time_sec / 60.0) * beats_per_minute
+			}
+		};
 
 		let mut wall_duration = wall.duration;
 		if wall_duration < 0.0 {
 			wall_duration = last_note_time_in_beats - wall_time_in_beats;
 		}
 		if wall_duration - wall.padding > 0.0 {
-			let wall_json = format!(
-				"{{
-					\"_time\": {},
-					\"_lineIndex\": {},
-					\"_lineLayer\": {},
-					\"_type\": {},
-					\"_duration\": {},
-					\"_width\": {}
-				}},",
-				wall_time_in_beats + wall.padding,
-				wall.x,
-				wall.y,
-				wall.wall_type,
-				wall_duration - wall.padding,
-				wall.width
-			);
-			obstacles_content.push_str(&wall_json);
+			let placed_wall = PatternWall {
+				duration: wall_duration - wall.padding,
+				padding: 0.0,
+				wall_time_rel: 0,
+				wall_time: Some(wall_time_in_beats + wall.padding),
+				..wall.clone()
+			};
+			state.obstacles_content.push_str(&create_wall_json(&placed_wall));
+			state.processed_walls.push(placed_wall);
 		}
 	}
-	let results: AddPatternResult = AddPatternResult {
-		pattern_end_time,
-		contents,
-		obstacles_content,
-	};
-	(results)
+
+	if notes_changed {
+		state.hand_states = hand_states_after_notes(&state.processed_notes);
+	}
 }
 /*
 * Generate a bsaber map using the information provided
 */
+#[allow(clippy::too_many_arguments)]
 fn generate_map(
-	mut contents: String,
+	rng: StdRng,
+	contents: String,
 	beats: &[Beat],
+	drops: &[Drop],
 	pattern_collection: &PatternCollection,
 	highest_pitch: f64,
 	lowest_pitch: f64,
 	beats_per_minute: f64,
+	vision_window: f64,
+	transition_model: &TransitionModel,
 	processed_notes: &mut Vec<PatternNote>,
-) -> String {
-	let mut obstacles_content: String = " ".to_owned();
-
-	let mut pattern_map = HashMap::new();
-	let mut pattern_end_time: f64 = 0.0;
-
-	//we'll treat the lowest 10% as nothing
-	let lowest_threshold = lowest_pitch * 1.10;
-
-	let hard_threshold = highest_pitch * 0.25;
-	let normal_threshold = highest_pitch * 0.65;
-
-	let mut beat_id = 0;
-
-	let mut left_prev_cut_direction: &CutDirection = &CUT_OMNI;
-	let mut left_prev_x = 1;
-	let mut left_prev_y = 2;
-	let mut right_prev_cut_direction: &CutDirection = &CUT_OMNI;
-	let mut right_prev_x = 2;
-	let mut right_prev_y = 2;
-
-	for beat in beats {
-		//println!("peak :{} vs {}",beat.peak_time_sec,pattern_end_time);
-		if beat.pitch > lowest_threshold && beat.peak_time_sec > pattern_end_time {
-			let dynamic_pattern_chance = rand::thread_rng().gen_range(0, 100);
-
-			if dynamic_pattern_chance <= CHANCE_FOR_DYNAMIC_PATTERN {
-				let new_pattern: Pattern = generate_dynamic_pattern(
-					left_prev_cut_direction,
-					left_prev_x,
-					left_prev_y,
-					right_prev_cut_direction,
-					right_prev_x,
-					right_prev_y,
-				);
+	settings: &GeneratorSettings,
+	stats: &mut GenerationStats,
+	write_history: bool,
+) -> Result<String, String> {
+	let phrase_sources = find_phrase_repeats(beats, &settings.phrases);
+	let intensities = intensity_scores(beats, &settings.intensity);
+	let density_plan = density_plan(beats, &intensities, &settings.density, settings.difficulty, beats_per_minute);
+	let context = MapContext {
+		beats,
+		pattern_collection,
+		lowest_pitch,
+		highest_pitch,
+		//we'll treat the lowest 10% as nothing
+		lowest_threshold: lowest_pitch * 1.10,
+		hard_threshold: highest_pitch * 0.25,
+		normal_threshold: highest_pitch * 0.65,
+		intensities: &intensities,
+		density_plan: density_plan.as_ref(),
+		beats_per_minute,
+		vision_window,
+		transition_model,
+		settings,
+		phrase_sources: &phrase_sources,
+		drops,
+		drop_response: settings.drop_response(),
+		thin_out_seconds: settings
+			.drop_response()
+			.map_or(0.0, |response| (response.thin_out_beats / beats_per_minute) * 60.0),
+		crossovers_allowed: settings.crossovers_allowed(),
+		log: settings.search.mode == SearchMode::Greedy,
+	};
 
-				for pattern_note in &new_pattern.notes {
-					if pattern_note.note_type == 0 {
-						left_prev_cut_direction =
-							&CUT_DIRECTIONS[pattern_note.cut_direction as usize];
-						left_prev_x = pattern_note.x as usize;
-						left_prev_y = pattern_note.y as usize;
-					} else if pattern_note.note_type == 1 {
-						right_prev_cut_direction =
-							&CUT_DIRECTIONS[pattern_note.cut_direction as usize];
-						right_prev_x = pattern_note.x as usize;
-						right_prev_y = pattern_note.y as usize;
-					}
-				}
-				let add_pattern_results: AddPatternResult = add_pattern(
-					&new_pattern,
-					contents,
-					beats,
-					processed_notes,
-					beat_id,
-					beats_per_minute,
-					pattern_end_time,
-					obstacles_content,
-				);
-				contents = add_pattern_results.contents;
-				pattern_end_time = add_pattern_results.pattern_end_time;
-				obstacles_content = add_pattern_results.obstacles_content;
-			} else {
-				let mut patterns = &pattern_collection.easy_patterns;
-				if beat.pitch <= hard_threshold {
-					patterns = &pattern_collection.normal_patterns;
-				} else if beat.pitch <= normal_threshold {
-					patterns = &pattern_collection.hard_patterns;
-				}
-				let random_pattern_id = rand::thread_rng().gen_range(0, patterns.len());
-
-				//x = rand::thread_rng().gen_range(0, 2);
-				let random_pattern = &patterns[random_pattern_id];
-
-				let pattern = pattern_map.entry(beat.id).or_insert(random_pattern);
-
-				let add_pattern_results: AddPatternResult = add_pattern(
-					pattern,
-					contents,
-					beats,
-					processed_notes,
-					beat_id,
-					beats_per_minute,
-					pattern_end_time,
-					obstacles_content,
-				);
-				contents = add_pattern_results.contents;
-				pattern_end_time = add_pattern_results.pattern_end_time;
-				obstacles_content = add_pattern_results.obstacles_content;
-			}
+	let header = contents.clone();
+	let mut state = MapState::new(rng, contents);
+	state.stats.repeated_phrase_peaks = phrase_sources.iter().filter(|source| source.is_some()).count();
+	state.stats.drops = drops.len();
+	state.stats.drops_after_build_up = drops.iter().filter(|drop| drop.build_up_start.is_some()).count();
+	if let Some(response) = context.drop_response {
+		state.stats.drop_thinned_peaks = beats
+			.iter()
+			.filter(|beat| {
+				beat.pitch > context.lowest_threshold
+					&& is_thinned_out(drops, context.thin_out_seconds, beat.peak_time_sec)
+			})
+			.count();
+		if response.lighting_hit {
+			state.stats.drop_lighting_hits = drops.len();
 		}
-		beat_id += 1;
+	}
+	let mut state = match settings.search.mode {
+		SearchMode::Greedy => greedy_search(state, &context),
+		SearchMode::Beam => beam_search(state, &context, &settings.search),
+	};
+	if settings.refine.generations > 0 {
+		let history_path = Some(settings.refine.history.as_str()).filter(|_| write_history);
+		refine_map(&mut state, &context, &header, &settings.refine, history_path)?;
 	}
 
 	//generate walls
@@ -788,16 +1291,452 @@ fn generate_map(
 				\"_width\": 1
 			}}]
 		}}",
-		obstacles_content
+		state.obstacles_content
 	)
 	.to_owned();
 
+	if let Some(plan) = &density_plan {
+		let end_in_beats = beats.last().map_or(0.0, |beat| (beat.peak_time_sec / 60.0) * beats_per_minute);
+		state.stats.density_sections = section_densities(plan, &state.processed_notes, end_in_beats, beats_per_minute);
+		state.stats.density_target_nps = plan.targets.iter().sum::<f64>() / plan.targets.len() as f64;
+		state.stats.density_nps = notes_per_second(&state.processed_notes, beats_per_minute);
+	}
+
 	//add to json string
-	contents.push_str(&contents_end);
+	state.contents.push_str(&contents_end);
+	*processed_notes = state.processed_notes;
+	*stats = state.stats;
 	let note_count = processed_notes.len();
 	println!("note count:{}", note_count.to_owned());
 	//return the completed json string
-	(contents)
+	Ok(state.contents)
+}
+
+//the library pools, from easy to hard
+const POOL_NAMES: [&str; 3] = ["easy", "normal", "hard"];
+
+/*
+* Which pool a peak picks from, 0 for easy, 1 for normal and 2 for hard.
+* The intensity of the music picks it, or the pitch of the peak in the legacy mode.
+*/
+fn pool_at(context: &MapContext, beat_id: usize) -> usize {
+	let beat = &context.beats[beat_id];
+	if context.settings.intensity.pool_mode == PoolMode::Intensity {
+		return pool_level(&context.settings.intensity, context.intensities[beat_id]);
+	}
+	if beat.pitch <= context.hard_threshold {
+		1
+	} else if beat.pitch <= context.normal_threshold {
+		2
+	} else {
+		0
+	}
+}
+
+/*
+* The library patterns a peak can pick from
+*/
+fn pattern_pool<'a>(context: &MapContext<'a>, beat_id: usize) -> &'a [Pattern] {
+	let pattern_collection = context.pattern_collection;
+	match pool_at(context, beat_id) {
+		0 => &pattern_collection.easy_patterns,
+		1 => &pattern_collection.normal_patterns,
+		_ => &pattern_collection.hard_patterns,
+	}
+}
+
+//whether a peak gets the bigger primitives, the hard pool's intensity or a high pitch in the legacy mode
+fn is_loud(context: &MapContext, beat_id: usize) -> bool {
+	match context.settings.intensity.pool_mode {
+		PoolMode::Intensity => pool_level(&context.settings.intensity, context.intensities[beat_id]) >= 2,
+		PoolMode::Pitch => context.beats[beat_id].pitch > context.normal_threshold,
+	}
+}
+
+//keep the pattern placed on a peak if a later phrase repeats it
+fn remember_phrase_pattern(state: &mut MapState, context: &MapContext, beat_id: usize, pattern: &Pattern) {
+	if context.phrase_sources.contains(&Some(beat_id)) {
+		state.phrase_patterns.insert(beat_id, Rc::new(pattern.clone()));
+	}
+}
+
+//whether the map does something on this peak because it's a drop
+fn is_drop_peak(context: &MapContext, beat_id: usize) -> bool {
+	context.drop_response.is_some() && context.drops.iter().any(|drop| drop.peak == beat_id)
+}
+
+//what the density target asks for at a peak, from the notes placed before it
+fn density_at(state: &MapState, context: &MapContext, beat_id: usize) -> DensityAdjust {
+	match context.density_plan {
+		Some(plan) => {
+			let time_in_beats = (context.beats[beat_id].peak_time_sec / 60.0) * context.beats_per_minute;
+			density_adjust(plan, &state.processed_notes, time_in_beats, context.beats_per_minute)
+		}
+		None => DensityAdjust::AsIs,
+	}
+}
+
+//the fewest and most steps a dynamic pattern or primitive gets
+fn pattern_steps(density: DensityAdjust) -> (usize, usize) {
+	match density {
+		DensityAdjust::Fewer => (SHORT_PATTERN_STEPS, MIN_PATTERN_STEPS),
+		DensityAdjust::More => (LONG_PATTERN_STEPS, MAX_DYNAMIC_PATTERN_NOTES),
+		_ => (MIN_PATTERN_STEPS, MAX_DYNAMIC_PATTERN_NOTES),
+	}
+}
+
+/*
+* A peak gets a pattern if it's loud enough, or a drop, and the last pattern has finished.
+* Peaks in the thin out before a drop are left empty, and quiet peaks are kept when the map is under its density target.
+*/
+fn beat_is_free(state: &MapState, context: &MapContext, beat_id: usize) -> bool {
+	let beat = &context.beats[beat_id];
+	if context.drop_response.is_some() && is_thinned_out(context.drops, context.thin_out_seconds, beat.peak_time_sec) {
+		return false;
+	}
+	//patterns timed in fractional beats start on the grid, which can be before the peak
+	let anchor_sec = (pattern_anchor_in_beats(context.beats, beat_id, context.beats_per_minute) / context.beats_per_minute) * 60.0;
+	if beat.peak_time_sec.min(anchor_sec) <= state.pattern_end_time {
+		return false;
+	}
+	beat.pitch > context.lowest_threshold
+		|| is_drop_peak(context, beat_id)
+		|| density_at(state, context, beat_id) == DensityAdjust::More
+}
+
+/*
+* Lead into a pattern with a bridge if the sabers don't flow into it. The bridge goes halfway, on the beat grid,
+* from the last note to the pattern's first note. It's left out if that leaves less than a grid step,
+* or less than the difficulty's shortest gap between swings, either side of it.
+*/
+fn place_bridge(state: &mut MapState, context: &MapContext, pattern: &Pattern, beat_id: usize) {
+	let beats_per_minute = context.beats_per_minute;
+	let pattern_start = pattern
+		.notes
+		.iter()
+		.map(|note| pattern_note_time(context.beats, beat_id, note, beats_per_minute).0)
+		.fold(f64::INFINITY, f64::min);
+	let last_note_time = state.processed_notes.iter().map(note_order).fold(0.0, f64::max);
+	let bridge_time = snap_to_beat_grid((last_note_time + pattern_start) / 2.0);
+	let beat_time = bridge_time - pattern_anchor_in_beats(context.beats, beat_id, beats_per_minute);
+	let bridge = match bridge_pattern(&state.hand_states, &pattern_flow(pattern), beat_time) {
+		Some(bridge) => bridge,
+		None => return,
+	};
+	let min_gap_seconds = context.settings.swing_limit().map_or(0.0, |limit| limit.min_gap_seconds);
+	let min_gap = ((min_gap_seconds / 60.0) * beats_per_minute).max(1.0 / PATTERN_GRID_DIVISION);
+	//a bridge hidden behind the last notes would be left out of the map anyway
+	let bridge_hidden = bridge.notes.iter().any(|note| {
+		let placed_note = PatternNote {
+			beat_time: Some(bridge_time),
+			..note.clone()
+		};
+		state
+			.processed_notes
+			.iter()
+			.any(|recent| hides(recent, &placed_note, context.vision_window))
+	});
+	if bridge_time - last_note_time < min_gap || pattern_start - bridge_time < min_gap || bridge_hidden {
+		state.stats.bridges_skipped += 1;
+		return;
+	}
+	state.stats.bridges += 1;
+	state.repetition_tracker.record(&context.settings.repetition, &bridge);
+	advance_hand_states(&mut state.hand_states, &bridge);
+	add_pattern(state, context, &bridge, beat_id);
+}
+
+/*
+* Place a pattern again on a repeated phrase if it keeps parity, the sabers stay out of each other's way
+* and the sabers flow into it like a library pick, with a bridge if they need one. Says whether it was placed.
+*/
+fn place_phrase_repeat(state: &mut MapState, context: &MapContext, pattern: &Pattern, beat_id: usize) -> bool {
+	if !pattern_keeps_parity(pattern) || !find_hand_issues(&pattern.notes, context.crossovers_allowed).is_empty() {
+		return false;
+	}
+	let flow = pattern_flow(pattern);
+	if !flow_fits(&state.hand_states, &flow) {
+		//try the bridge on a copy, a bridge that doesn't lead into the repeat stays out of the map
+		let mut bridged = state.clone();
+		place_bridge(&mut bridged, context, pattern, beat_id);
+		if !flow_fits(&bridged.hand_states, &flow) {
+			return false;
+		}
+		*state = bridged;
+	}
+	state.stats.phrase_repeats += 1;
+	*state.stats.phrase_repeat_usage.entry(template_base_id(pattern).to_owned()).or_insert(0) += 1;
+	state.repetition_tracker.record(&context.settings.repetition, pattern);
+	advance_hand_states(&mut state.hand_states, pattern);
+	add_pattern(state, context, pattern, beat_id);
+	true
+}
+
+/*
+* The signature pattern and wall burst on a drop
+*/
+fn place_drop(state: &mut MapState, context: &MapContext, response: &DropResponse, beat_id: usize) {
+	let mut pattern = Pattern {
+		id: "drop".to_owned(),
+		template_id: None,
+		source: PatternSource::Drop,
+		description: "Drop".to_owned(),
+		notes: Vec::new(),
+		obstacles: Vec::new(),
+	};
+	//a map well over its density target only gets the walls and lights on the drop
+	let density = density_at(state, context, beat_id);
+	if response.signature && density == DensityAdjust::Drop {
+		state.stats.density_dropped_peaks += 1;
+	}
+	if response.signature && density != DensityAdjust::Drop {
+		let pattern_collection = context.pattern_collection;
+		//a library signature has to flow on from the sabers, there's no room for a bridge on the drop
+		let library_pattern = response.signature_pattern.as_ref().and_then(|signature_id| {
+			pattern_collection
+				.easy_patterns
+				.iter()
+				.chain(pattern_collection.normal_patterns.iter())
+				.chain(pattern_collection.hard_patterns.iter())
+				.find(|pattern| &pattern.id == signature_id)
+				.filter(|pattern| flow_fits(&state.hand_states, &pattern_flow(pattern)))
+		});
+		pattern = match library_pattern {
+			Some(library_pattern) => library_pattern.clone(),
+			None => {
+				let steps = match density {
+					DensityAdjust::Fewer => {
+						state.stats.density_shorter_patterns += 1;
+						SHORT_SIGNATURE_JUMP_STEPS
+					}
+					DensityAdjust::More => {
+						state.stats.density_longer_patterns += 1;
+						LONG_SIGNATURE_JUMP_STEPS
+					}
+					_ => SIGNATURE_JUMP_STEPS,
+				};
+				generate_primitive(
+					Primitive::Jump,
+					&PrimitiveParams {
+						hand_states: state.hand_states,
+						steps,
+						step_beats: SIGNATURE_JUMP_STEP_BEATS,
+					},
+				)
+			}
+		};
+		state.stats.drop_signatures += 1;
+		*state.stats.drop_usage.entry(template_base_id(&pattern).to_owned()).or_insert(0) += 1;
+	}
+	pattern.obstacles.extend(burst_walls(&pattern, response.wall_burst_beats));
+	if !pattern.obstacles.is_empty() {
+		state.stats.drop_wall_bursts += 1;
+	}
+
+	state.repetition_tracker.record(&context.settings.repetition, &pattern);
+	advance_hand_states(&mut state.hand_states, &pattern);
+	add_pattern(state, context, &pattern, beat_id);
+	//nothing starts in the outside lanes until the burst is over
+	let burst_end_in_beats =
+		pattern_anchor_in_beats(context.beats, beat_id, context.beats_per_minute) + response.wall_burst_beats;
+	let burst_end_sec = (burst_end_in_beats / context.beats_per_minute) * 60.0;
+	state.pattern_end_time = state.pattern_end_time.max(burst_end_sec);
+}
+
+/*
+* Place whatever goes on a peak, returns false if the peak is skipped
+*/
+fn place_beat<'a>(state: &mut MapState<'a>, context: &MapContext<'a>, beat_id: usize) -> bool {
+	let beats = context.beats;
+	let beat = &beats[beat_id];
+	let settings = context.settings;
+	//println!("peak :{} vs {}",beat.peak_time_sec,pattern_end_time);
+	if !beat_is_free(state, context, beat_id) {
+		return false;
+	}
+	if let Some(response) = context.drop_response.filter(|_| is_drop_peak(context, beat_id)) {
+		if response.signature || response.wall_burst_beats > 0.0 {
+			place_drop(state, context, response, beat_id);
+			return true;
+		}
+	}
+	//the density target can leave the peak empty, and makes the patterns on it shorter or longer
+	let density = density_at(state, context, beat_id);
+	if density == DensityAdjust::Drop {
+		state.stats.density_dropped_peaks += 1;
+		return false;
+	}
+
+	//a phrase that sounds like an earlier one gets the same patterns on the same peaks,
+	//and nothing where the earlier phrase was in the middle of a pattern or too quiet, unless the map needs more notes
+	let phrase_pattern = match context.phrase_sources[beat_id] {
+		Some(source_id) => match state.phrase_patterns.get(&source_id) {
+			Some(source_pattern) => Some(source_pattern.clone()),
+			None if density != DensityAdjust::More => return false,
+			None => None,
+		},
+		None => None,
+	};
+	if beat.pitch <= context.lowest_threshold {
+		state.stats.density_kept_peaks += 1;
+	}
+
+	//a long enough gap is a section boundary, where the sabers can start again on either swing
+	let gap_in_beats = ((beat.peak_time_sec - state.pattern_end_time) / 60.0) * context.beats_per_minute;
+	if state.pattern_end_time > 0.0
+		&& is_reset_gap(&settings.parity, gap_in_beats)
+		&& state.hand_states.iter().any(|hand_state| hand_state.swing.is_some())
+	{
+		state.stats.parity_resets += 1;
+		for hand_state in state.hand_states.iter_mut() {
+			hand_state.swing = None;
+		}
+	}
+	if let Some(source_pattern) = phrase_pattern {
+		let pattern = if settings.phrases.mirror_repeats {
+			Rc::new(mirror_pattern(&source_pattern))
+		} else {
+			source_pattern
+		};
+		if place_phrase_repeat(state, context, &pattern, beat_id) {
+			return true;
+		}
+		//a repeat the sabers can't get into gets a pattern picked as usual
+		state.stats.phrase_repeat_misses += 1;
+	}
+	match density {
+		DensityAdjust::Fewer => state.stats.density_shorter_patterns += 1,
+		DensityAdjust::More => state.stats.density_longer_patterns += 1,
+		_ => {}
+	}
+	let steps = pattern_steps(density);
+
+	let dynamic_pattern_chance = state.rng.gen_range(0, 100);
+
+	if dynamic_pattern_chance < settings.primitives.chance {
+		//the spacing of the peaks picks the primitive and how fast it goes
+		let step_beats = snap_to_beat_grid(peak_gap_in_beats(beats, beat_id + 1, context.beats_per_minute))
+			.clamp(MIN_PRIMITIVE_STEP_BEATS, MAX_PRIMITIVE_STEP_BEATS);
+		let loud = is_loud(context, beat_id);
+		let primitive = choose_primitive(&mut state.rng, step_beats, loud, context.crossovers_allowed);
+		let params = PrimitiveParams {
+			hand_states: state.hand_states,
+			steps: state.rng.gen_range(steps.0, steps.1 + 1),
+			step_beats,
+		};
+		let new_pattern = generate_primitive(primitive, &params);
+
+		*state.stats.primitive_usage.entry(primitive.name().to_owned()).or_insert(0) += 1;
+		state.repetition_tracker.record(&settings.repetition, &new_pattern);
+		advance_hand_states(&mut state.hand_states, &new_pattern);
+		add_pattern(state, context, &new_pattern, beat_id);
+		remember_phrase_pattern(state, context, beat_id, &new_pattern);
+	} else if dynamic_pattern_chance <= CHANCE_FOR_DYNAMIC_PATTERN || pattern_pool(context, beat_id).is_empty() {
+		//a dynamic pattern also stands in when the library has nothing for this peak
+		//the time (in beats) before each step of the pattern, from the peaks it'll be placed on, and the peak after it
+		let step_gaps: Vec<f64> = (0..MAX_DYNAMIC_PATTERN_NOTES + 1)
+			.map(|time_rel| peak_gap_in_beats(beats, beat_id + time_rel + 1, context.beats_per_minute))
+			.collect();
+		let pitch_hints: Option<Vec<PitchHint>> = if settings.pitch.layers_and_cuts {
+			let position =
+				|peak_id: usize| pitch_position(beats[peak_id].pitch, context.lowest_pitch, context.highest_pitch);
+			Some(
+				(0..MAX_DYNAMIC_PATTERN_NOTES)
+					.map(|time_rel| {
+						let peak_id = (beat_id + time_rel + 1).min(beats.len() - 1);
+						pitch_hint(&settings.pitch, position(peak_id.saturating_sub(1)), position(peak_id))
+					})
+					.collect(),
+			)
+		} else {
+			None
+		};
+		let mut new_pattern: Pattern = generate_dynamic_pattern(
+			&mut state.rng,
+			&state.hand_states,
+			context.transition_model,
+			&step_gaps,
+			steps,
+			pitch_hints.as_deref(),
+			context.vision_window,
+			&mut state.stats,
+		);
+		//try again if the sabers cross, clash or swing through each other, keeping the last attempt if they all do
+		for _ in 1..MAX_DYNAMIC_PATTERN_ATTEMPTS {
+			if find_hand_issues(&new_pattern.notes, context.crossovers_allowed).is_empty() {
+				break;
+			}
+			state.stats.hand_issue_retries += 1;
+			new_pattern = generate_dynamic_pattern(
+				&mut state.rng,
+				&state.hand_states,
+				context.transition_model,
+				&step_gaps,
+				steps,
+				pitch_hints.as_deref(),
+				context.vision_window,
+				&mut state.stats,
+			);
+		}
+
+		state.stats.dynamic_patterns += 1;
+		state.repetition_tracker.record(&settings.repetition, &new_pattern);
+		advance_hand_states(&mut state.hand_states, &new_pattern);
+		add_pattern(state, context, &new_pattern, beat_id);
+		remember_phrase_pattern(state, context, beat_id, &new_pattern);
+	} else {
+		let patterns = pattern_pool(context, beat_id);
+		//prefer patterns whose first swings follow on from where the sabers are now,
+		//that keep alternating forehand and backhand all the way through
+		//and where the sabers stay out of each other's way
+		let mut candidate_patterns: Vec<&Pattern> = patterns
+			.iter()
+			.filter(|pattern| {
+				flow_fits(&state.hand_states, &pattern_flow(pattern))
+					&& pattern_keeps_parity(pattern)
+					&& find_hand_issues(&pattern.notes, context.crossovers_allowed).is_empty()
+			})
+			.collect();
+		if candidate_patterns.is_empty() {
+			candidate_patterns = patterns.iter().collect();
+		}
+		let candidate_patterns = prefer_pattern_length(candidate_patterns, density);
+
+		//in motif mode the same pitch bin gets the same pattern every time it comes back
+		let pattern = match state.pattern_map.get(&beat.id) {
+			Some(motif_pattern) if settings.repetition.motif_mode => {
+				state.stats.motif_reuses += 1;
+				*motif_pattern
+			}
+			_ => {
+				let pattern = choose_pattern(
+					&mut state.rng,
+					&candidate_patterns,
+					&state.repetition_tracker,
+					&settings.repetition,
+					&mut state.stats,
+				);
+				//nothing to pick from leaves the peak empty
+				let pattern = match pattern {
+					Some(pattern) => pattern,
+					None => return false,
+				};
+				state.pattern_map.insert(beat.id, pattern);
+				pattern
+			}
+		};
+
+		//nothing fits, so lead into the pattern with a bridge
+		place_bridge(state, context, pattern, beat_id);
+
+		*state.stats.pattern_usage.entry(template_base_id(pattern).to_owned()).or_insert(0) += 1;
+		*state.stats.pool_usage.entry(POOL_NAMES[pool_at(context, beat_id)].to_owned()).or_insert(0) += 1;
+		state.repetition_tracker.record(&settings.repetition, pattern);
+		advance_hand_states(&mut state.hand_states, pattern);
+		add_pattern(state, context, pattern, beat_id);
+		remember_phrase_pattern(state, context, beat_id, pattern);
+	}
+	true
 }
 /*
 	* Creates a note json string based on the date provided
@@ -829,12 +1768,35 @@ fn create_note_json(
 		id, peak_time, peak_pitch, time_beats, x, y, note_type, cut_direction, description
 	)
 	.to_owned();
-	(note)
+	note
+}
+
+/*
+* The obstacle json of a placed wall, timed in beats from the start of the map
+*/
+fn create_wall_json(wall: &PatternWall) -> String {
+	format!(
+		"{{
+					\"_time\": {},
+					\"_lineIndex\": {},
+					\"_lineLayer\": {},
+					\"_type\": {},
+					\"_duration\": {},
+					\"_width\": {}
+				}},",
+		wall.wall_time.unwrap_or(0.0),
+		wall.x,
+		wall.y,
+		wall.wall_type,
+		wall.duration,
+		wall.width
+	)
 }
 
 /*
 	* Zip up the important files from /src/song
 	* This is the zip beat saber, bsaber, or the map previewer can use
+	* The files are read when zipping so edits to info.dat end up in the zip without a rebuild
  */
 fn create_zip_archive<T: Seek + Write>(buf: &mut T, song_json: String) -> ZipResult<()> {
 	let mut writer = ZipWriter::new(buf);
@@ -844,20 +1806,20 @@ fn create_zip_archive<T: Seek + Write>(buf: &mut T, song_json: String) -> ZipRes
 	writer.write_all(song_json.as_bytes())?;
 
 	println!("Adding cover");
-	let cover = include_bytes!("./song/cover.jpg");
+	let cover = fs::read("src/song/cover.jpg")?;
 	writer.start_file("cover.jpg", FileOptions::default())?;
-	writer.write_all(cover)?;
+	writer.write_all(&cover)?;
 	println!("Adding info");
-	let info = include_bytes!("./song/info.dat");
+	let info = fs::read("src/song/info.dat")?;
 	writer.start_file("info.dat", FileOptions::default())?;
-	writer.write_all(info)?;
+	writer.write_all(&info)?;
 	println!("Adding song");
-	let song = include_bytes!("./song/song.ogg");
+	let song = fs::read("src/song/song.ogg")?;
 	writer.start_file("song.ogg", FileOptions::default())?;
-	writer.write_all(song)?;
-	let song_egg = include_bytes!("./song/song.egg");
+	writer.write_all(&song)?;
+	let song_egg = fs::read("src/song/song.egg")?;
 	writer.start_file("song.egg", FileOptions::default())?;
-	writer.write_all(song_egg)?;
+	writer.write_all(&song_egg)?;
 	println!("Adding done");
 	writer.finish()?;
 	Ok(())
@@ -0,0 +1,259 @@
+use crate::beatmap::{DifficultyFile, MapNote};
+use crate::flow::SAME_TIME_TOLERANCE;
+use crate::settings::TransitionSettings;
+use glob::glob;
+use rand::rngs::StdRng;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+const DEFAULT_MODEL_PATH: &str = "src/song/transition_model.json";
+//upper bounds (in beats) of the time gap buckets, longer gaps go in the last bucket
+const GAP_BUCKETS: [f64; 3] = [0.25, 0.5, 1.0];
+//notes further apart than this (in beats) don't follow on from each other
+const MAX_TRANSITION_GAP: f64 = 4.0;
+
+/*
+* How often each transition happens in a folder of maps, per hand and time gap bucket.
+* Cuts are keyed by "hand/gap/previous cut/previous x/previous y" with counts indexed by cut direction,
+* columns by "hand/gap/cut/previous x" and layers by "hand/gap/cut/previous y".
+*/
+#[derive(Serialize, Deserialize, Default)]
+pub struct LearnedModel {
+	pub maps: usize,
+	pub transitions: usize,
+	pub cuts: BTreeMap<String, Vec<usize>>,
+	pub columns: BTreeMap<String, Vec<usize>>,
+	pub layers: BTreeMap<String, Vec<usize>>,
+}
+
+/*
+* Where dynamic patterns get their next cut and position from.
+* HardCoded is the possible_transitions table and get_next_x/get_next_y, a learned model
+* falls back to it for any transition it has never seen.
+*/
+pub enum TransitionModel {
+	HardCoded,
+	Learned(LearnedModel),
+}
+
+impl TransitionModel {
+	pub fn cut_weights(&self, hand: i64, gap: f64, prev_cut: i64, prev_x: usize, prev_y: usize) -> Option<&Vec<usize>> {
+		match self {
+			TransitionModel::Learned(model) => model.cuts.get(&format!(
+				"{}/{}/{}/{}/{}",
+				hand,
+				gap_bucket(gap),
+				prev_cut,
+				prev_x,
+				prev_y
+			)),
+			TransitionModel::HardCoded => None,
+		}
+	}
+
+	pub fn column_weights(&self, hand: i64, gap: f64, cut: i64, prev_x: usize) -> Option<&Vec<usize>> {
+		match self {
+			TransitionModel::Learned(model) => {
+				model.columns.get(&format!("{}/{}/{}/{}", hand, gap_bucket(gap), cut, prev_x))
+			}
+			TransitionModel::HardCoded => None,
+		}
+	}
+
+	pub fn layer_weights(&self, hand: i64, gap: f64, cut: i64, prev_y: usize) -> Option<&Vec<usize>> {
+		match self {
+			TransitionModel::Learned(model) => {
+				model.layers.get(&format!("{}/{}/{}/{}", hand, gap_bucket(gap), cut, prev_y))
+			}
+			TransitionModel::HardCoded => None,
+		}
+	}
+}
+
+pub fn gap_bucket(gap: f64) -> usize {
+	GAP_BUCKETS
+		.iter()
+		.position(|bucket| gap <= *bucket)
+		.unwrap_or(GAP_BUCKETS.len())
+}
+
+/*
+* Pick an index with a chance proportional to its weight, None if every weight is 0
+*/
+pub fn sample_weighted(rng: &mut StdRng, weights: &[usize]) -> Option<usize> {
+	let total: usize = weights.iter().sum();
+	if total == 0 {
+		return None;
+	}
+	let mut choice = rng.gen_range(0, total);
+	for (index, weight) in weights.iter().enumerate() {
+		if choice < *weight {
+			return Some(index);
+		}
+		choice -= weight;
+	}
+	None
+}
+
+/*
+* The model set in generator.json, or the hard-coded table if there isn't one.
+* A model that can't be parsed or has counts that don't fit the cut directions or the 4x3 grid is an error.
+*/
+pub fn load_transition_model(settings: &TransitionSettings) -> Result<TransitionModel, String> {
+	let model_path = match &settings.model {
+		Some(model_path) => model_path,
+		None => return Ok(TransitionModel::HardCoded),
+	};
+	match fs::read_to_string(model_path) {
+		Ok(model_json) => {
+			let model: LearnedModel = serde_json::from_str(&model_json)
+				.map_err(|error| format!("Could not parse transition model {}: {}", model_path, error))?;
+			check_counts(&model.cuts, 9, "cuts", model_path)?;
+			check_counts(&model.columns, 4, "columns", model_path)?;
+			check_counts(&model.layers, 3, "layers", model_path)?;
+			Ok(TransitionModel::Learned(model))
+		}
+		Err(_) => {
+			println!("Transition model {} not found, using the built in transitions", model_path);
+			Ok(TransitionModel::HardCoded)
+		}
+	}
+}
+
+//every count list has one count per cut direction, column or layer, so a sampled index is always on the grid
+fn check_counts(counts: &BTreeMap<String, Vec<usize>>, size: usize, name: &str, model_path: &str) -> Result<(), String> {
+	match counts.iter().find(|(_, counts)| counts.len() != size) {
+		Some((key, key_counts)) => Err(format!(
+			"Transition model {} has {} counts for {} {}, expected {}",
+			model_path,
+			key_counts.len(),
+			name,
+			key,
+			size
+		)),
+		None => Ok(()),
+	}
+}
+
+fn add_count(counts: &mut BTreeMap<String, Vec<usize>>, key: String, size: usize, index: usize) {
+	counts.entry(key).or_insert_with(|| vec![0; size])[index] += 1;
+}
+
+/*
+* Count the transitions between consecutive notes of each hand.
+* Notes off the 4x3 grid or with mapping extension cut directions are skipped.
+*/
+pub fn train_model(difficulty_files: &[DifficultyFile]) -> LearnedModel {
+	let mut model = LearnedModel {
+		maps: difficulty_files.len(),
+		..LearnedModel::default()
+	};
+	for difficulty_file in difficulty_files {
+		let mut notes: Vec<&MapNote> = difficulty_file
+			._notes
+			.iter()
+			.filter(|note| {
+				(note._type == 0 || note._type == 1)
+					&& (0..4).contains(&note._lineIndex)
+					&& (0..3).contains(&note._lineLayer)
+					&& (0..9).contains(&note._cutDirection)
+			})
+			.collect();
+		notes.sort_by(|a, b| a._time.partial_cmp(&b._time).unwrap());
+
+		let mut prev_notes: [Option<&MapNote>; 2] = [None, None];
+		for note in notes {
+			let hand = note._type as usize;
+			if let Some(prev_note) = prev_notes[hand] {
+				let gap = note._time - prev_note._time;
+				if gap <= SAME_TIME_TOLERANCE {
+					continue;
+				}
+				if gap <= MAX_TRANSITION_GAP {
+					let bucket = gap_bucket(gap);
+					add_count(
+						&mut model.cuts,
+						format!(
+							"{}/{}/{}/{}/{}",
+							hand, bucket, prev_note._cutDirection, prev_note._lineIndex, prev_note._lineLayer
+						),
+						9,
+						note._cutDirection as usize,
+					);
+					add_count(
+						&mut model.columns,
+						format!("{}/{}/{}/{}", hand, bucket, note._cutDirection, prev_note._lineIndex),
+						4,
+						note._lineIndex as usize,
+					);
+					add_count(
+						&mut model.layers,
+						format!("{}/{}/{}/{}", hand, bucket, note._cutDirection, prev_note._lineLayer),
+						3,
+						note._lineLayer as usize,
+					);
+					model.transitions += 1;
+				}
+			}
+			prev_notes[hand] = Some(note);
+		}
+	}
+	model
+}
+
+/*
+* cargo run -- train ~/maps                            train on every difficulty in a folder of maps
+* cargo run -- train ~/maps src/song/model.json        and save the model somewhere else
+* Set "transitions": {"model": "src/song/transition_model.json"} in generator.json to use it.
+*/
+pub fn run(args: &[String]) {
+	let maps_folder = match args.first() {
+		Some(maps_folder) => maps_folder,
+		None => {
+			println!("Usage: train <maps folder> [model file]");
+			return;
+		}
+	};
+	let model_path = args.get(1).map(|path| path.as_str()).unwrap_or(DEFAULT_MODEL_PATH);
+
+	let mut difficulty_files = Vec::new();
+	let maps_glob = format!("{}/**/*.dat", maps_folder);
+	for entry in glob(&maps_glob).expect("Failed to read glob pattern") {
+		match entry {
+			Ok(path) => {
+				let is_info = path
+					.file_name()
+					.is_some_and(|file_name| file_name.to_string_lossy().eq_ignore_ascii_case("info.dat"));
+				if is_info {
+					continue;
+				}
+				let difficulty_json = match fs::read_to_string(&path) {
+					Ok(difficulty_json) => difficulty_json,
+					Err(e) => {
+						println!("Skipping {}: {}", path.display(), e);
+						continue;
+					}
+				};
+				match serde_json::from_str::<DifficultyFile>(&difficulty_json) {
+					Ok(difficulty_file) => difficulty_files.push(difficulty_file),
+					Err(e) => println!("Skipping {}: {}", path.display(), e),
+				}
+			}
+			Err(e) => println!("{:?}", e),
+		}
+	}
+
+	let model = train_model(&difficulty_files);
+	let model_json = serde_json::to_string_pretty(&model).expect("Could not convert model to json");
+	if let Some(model_folder) = Path::new(model_path).parent() {
+		fs::create_dir_all(model_folder).expect("Could not create model folder");
+	}
+	fs::write(model_path, model_json).expect("Could not write transition model");
+	println!(
+		"Trained on {} transitions from {} maps, written to {}",
+		model.transitions, model.maps, model_path
+	);
+}
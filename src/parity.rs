@@ -0,0 +1,63 @@
+use crate::flow::{next_swing, note_order, swing_for_cut, Swing, SAME_TIME_TOLERANCE};
+use crate::settings::ParitySettings;
+use crate::{Pattern, PatternNote};
+
+/*
+* A note breaks parity when it needs the same swing as the hand's last swing,
+* so the player has to reset or swing the same way twice. Dots never break it.
+*/
+pub fn breaks_parity(prev_swing: Option<Swing>, note_type: i64, cut_direction: i64) -> bool {
+	match (prev_swing, swing_for_cut(note_type, cut_direction)) {
+		(Some(prev_swing), Some(swing)) => prev_swing == swing,
+		_ => false,
+	}
+}
+
+/*
+* A section boundary is a gap long enough that a hand can reset without it being a mistake.
+* Only counts when resets are allowed in the settings.
+*/
+pub fn is_reset_gap(settings: &ParitySettings, gap_in_beats: f64) -> bool {
+	settings.allow_resets && gap_in_beats >= settings.reset_gap_beats
+}
+
+/*
+* Follow each saber through the notes in order and return every note that swings the same way as the one before it.
+* Without settings the notes are checked on their own, with them a reset after a long enough gap is allowed.
+*/
+pub fn find_parity_breaks<'a>(
+	notes: &'a [PatternNote],
+	settings: Option<&ParitySettings>,
+) -> Vec<&'a PatternNote> {
+	let mut hand_notes: Vec<&PatternNote> = notes
+		.iter()
+		.filter(|note| note.note_type == 0 || note.note_type == 1)
+		.collect();
+	hand_notes.sort_by(|a, b| note_order(a).partial_cmp(&note_order(b)).unwrap());
+
+	let mut swings: [Option<Swing>; 2] = [None, None];
+	let mut last_times: [Option<f64>; 2] = [None, None];
+	let mut breaks = Vec::new();
+	for note in hand_notes {
+		let hand = note.note_type as usize;
+		let time = note_order(note);
+		match last_times[hand] {
+			//stacks and windows are one swing
+			Some(last_time) if time - last_time <= SAME_TIME_TOLERANCE => continue,
+			Some(last_time) if settings.is_some_and(|settings| is_reset_gap(settings, time - last_time)) => {
+				swings[hand] = None;
+			}
+			_ => {}
+		}
+		if breaks_parity(swings[hand], note.note_type, note.cut_direction) {
+			breaks.push(note);
+		}
+		swings[hand] = next_swing(swings[hand], note.note_type, note.cut_direction);
+		last_times[hand] = Some(time);
+	}
+	breaks
+}
+
+pub fn pattern_keeps_parity(pattern: &Pattern) -> bool {
+	find_parity_breaks(&pattern.notes, None).is_empty()
+}
@@ -0,0 +1,96 @@
+use crate::flow::GRID_COLUMNS;
+use crate::settings::PhraseSettings;
+use crate::template::mirror_cut;
+use crate::{Beat, Pattern, PatternNote, PatternWall};
+
+/*
+* How alike two chroma vectors are, from 0 to 1. A silent peak is like nothing.
+*/
+fn chroma_similarity(first: &[f64], second: &[f64]) -> f64 {
+	let dot: f64 = first.iter().zip(second.iter()).map(|(a, b)| a * b).sum();
+	let first_length = first.iter().map(|a| a * a).sum::<f64>().sqrt();
+	let second_length = second.iter().map(|b| b * b).sum::<f64>().sqrt();
+	if first_length == 0.0 || second_length == 0.0 {
+		return 0.0;
+	}
+	dot / (first_length * second_length)
+}
+
+//how alike the phrases starting at two peaks are, peak by peak
+fn phrase_similarity(beats: &[Beat], first_start: usize, second_start: usize, phrase_peaks: usize) -> f64 {
+	let total: f64 = (0..phrase_peaks)
+		.map(|offset| chroma_similarity(&beats[first_start + offset].chroma, &beats[second_start + offset].chroma))
+		.sum();
+	total / phrase_peaks as f64
+}
+
+/*
+* For every peak in a phrase that sounds like an earlier one, the peak it repeats.
+* Phrases are phrase_peaks long and start every phrase_peaks peaks, the earlier phrase can start on any peak
+* as long as it ends before the phrase does. The most alike earlier phrase at or over the threshold wins,
+* and repeats point back to the first time the phrase was heard. Nothing repeats without chroma for every peak.
+*/
+pub fn find_phrase_repeats(beats: &[Beat], settings: &PhraseSettings) -> Vec<Option<usize>> {
+	let mut sources = vec![None; beats.len()];
+	let phrase_peaks = settings.phrase_peaks;
+	if !settings.enabled || phrase_peaks == 0 || beats.iter().any(|beat| beat.chroma.is_empty()) {
+		return sources;
+	}
+
+	let mut phrase_start = phrase_peaks;
+	while phrase_start + phrase_peaks <= beats.len() {
+		let mut best: Option<(usize, f64)> = None;
+		for earlier_start in 0..=phrase_start - phrase_peaks {
+			let similarity = phrase_similarity(beats, earlier_start, phrase_start, phrase_peaks);
+			//ties go to the earliest phrase
+			let is_best = best.is_none_or(|(_, best_similarity)| similarity > best_similarity);
+			if similarity >= settings.similarity_threshold && is_best {
+				best = Some((earlier_start, similarity));
+			}
+		}
+		if let Some((earlier_start, _)) = best {
+			for offset in 0..phrase_peaks {
+				let source = earlier_start + offset;
+				sources[phrase_start + offset] = Some(sources[source].unwrap_or(source));
+			}
+		}
+		phrase_start += phrase_peaks;
+	}
+	sources
+}
+
+/*
+* The same pattern seen in a left/right mirror, the sabers swap over
+*/
+pub fn mirror_pattern(pattern: &Pattern) -> Pattern {
+	let notes = pattern
+		.notes
+		.iter()
+		.map(|note| PatternNote {
+			x: GRID_COLUMNS - 1 - note.x,
+			cut_direction: mirror_cut(note.cut_direction),
+			note_type: match note.note_type {
+				0 => 1,
+				1 => 0,
+				note_type => note_type,
+			},
+			..note.clone()
+		})
+		.collect();
+	let obstacles = pattern
+		.obstacles
+		.iter()
+		.map(|wall| PatternWall {
+			x: GRID_COLUMNS - wall.x - wall.width.round() as i64,
+			..wall.clone()
+		})
+		.collect();
+	Pattern {
+		id: pattern.id.to_owned(),
+		template_id: pattern.template_id.clone(),
+		source: pattern.source,
+		description: format!("{} mirrored", pattern.description),
+		notes,
+		obstacles,
+	}
+}
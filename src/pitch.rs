@@ -0,0 +1,61 @@
+use crate::settings::PitchSettings;
+
+const CUT_UP: i64 = 0;
+const CUT_DOWN: i64 = 1;
+
+/*
+* What the pitch of a peak suggests for the notes on it: the layer, and an up or down cut
+* if the melody rose or fell to get there
+*/
+#[derive(Clone, Copy)]
+pub struct PitchHint {
+	pub layer: i64,
+	pub cut_direction: Option<i64>,
+}
+
+/*
+* How far a pitch is from the song's lowest pitch to its highest, from 0 to 1
+*/
+pub fn pitch_position(pitch: f64, lowest_pitch: f64, highest_pitch: f64) -> f64 {
+	if highest_pitch <= lowest_pitch {
+		return 0.0;
+	}
+	((pitch - lowest_pitch) / (highest_pitch - lowest_pitch)).clamp(0.0, 1.0)
+}
+
+//higher pitches go on higher layers, each layer starts where its position is reached
+pub fn layer_for_position(settings: &PitchSettings, position: f64) -> i64 {
+	settings
+		.layer_starts
+		.iter()
+		.filter(|layer_start| position >= **layer_start)
+		.count() as i64
+}
+
+//a rising melody cuts up and a falling one cuts down, small changes are flat and leave the cut alone
+pub fn contour_cut(settings: &PitchSettings, prev_position: f64, position: f64) -> Option<i64> {
+	let change = position - prev_position;
+	if change > settings.contour_tolerance {
+		Some(CUT_UP)
+	} else if change < -settings.contour_tolerance {
+		Some(CUT_DOWN)
+	} else {
+		None
+	}
+}
+
+//low pitches go to the left saber and high pitches to the right
+pub fn hand_for_position(settings: &PitchSettings, position: f64) -> i64 {
+	if position < settings.hand_split {
+		0
+	} else {
+		1
+	}
+}
+
+pub fn pitch_hint(settings: &PitchSettings, prev_position: f64, position: f64) -> PitchHint {
+	PitchHint {
+		layer: layer_for_position(settings, position),
+		cut_direction: contour_cut(settings, prev_position, position),
+	}
+}
@@ -0,0 +1,163 @@
+use crate::flow::HandState;
+use crate::parity::breaks_parity;
+use crate::swing::{is_uncomfortable, swing_transition};
+use crate::vision::is_vision_blocker;
+use crate::PatternNote;
+
+//how much each soft constraint costs when the request has to move, a legal request is kept as it is
+const REQUEST_DISTANCE_COST: f64 = 1.0;
+const CHANGED_CUT_COST: f64 = 4.0;
+const TRAVEL_COST: f64 = 0.5;
+const UNCOMFORTABLE_SWING_COST: f64 = 3.0;
+//bottom layer first, the top layer is the furthest to reach
+const LAYER_COSTS: [f64; 3] = [0.0, 0.25, 0.5];
+
+/*
+* Everything a dynamic note has to fit around
+*/
+pub struct PlacementConstraints<'a> {
+	//where the saber's last note was and which way it swung
+	pub hand_state: &'a HandState,
+	//the other saber's note at the same step, if it has one
+	pub other_note: Option<&'a PatternNote>,
+	//time (in beats) from the saber's last note, and on to the next step of the pattern if there is one
+	pub gap: f64,
+	pub next_gap: Option<f64>,
+	//how long (in beats) a note in the middle of the grid hides the notes behind it
+	pub vision_window: f64,
+}
+
+/*
+* Whether a placement breaks a hard constraint:
+* overlap, the other saber's note is in the same cell,
+* hand side, the note is past the other saber's note or in the far column for its saber,
+* parity, the cut swings the same way as the saber's last swing,
+* vision, the note is in the middle of the grid and the next step comes before it's out of the way
+*/
+fn breaks_hard_constraint(note: &PatternNote, constraints: &PlacementConstraints) -> bool {
+	if let Some(other_note) = constraints.other_note {
+		if other_note.x == note.x && other_note.y == note.y {
+			return true;
+		}
+		let crossed = if note.note_type == 0 {
+			note.x > other_note.x
+		} else {
+			note.x < other_note.x
+		};
+		if crossed {
+			return true;
+		}
+	}
+	if (note.note_type == 0 && note.x == 3) || (note.note_type == 1 && note.x == 0) {
+		return true;
+	}
+	if breaks_parity(constraints.hand_state.swing, note.note_type, note.cut_direction) {
+		return true;
+	}
+	let next_step_hidden = constraints
+		.next_gap
+		.is_some_and(|next_gap| next_gap < constraints.vision_window);
+	is_vision_blocker(note) && next_step_hidden
+}
+
+fn placement_cost(note: &PatternNote, request: &PatternNote, constraints: &PlacementConstraints) -> f64 {
+	let distance = |x: i64, y: i64| (((note.x - x).pow(2) + (note.y - y).pow(2)) as f64).sqrt();
+	let hand_state = constraints.hand_state;
+	let prev_note = PatternNote {
+		x: hand_state.x,
+		y: hand_state.y,
+		cut_direction: hand_state.cut_direction,
+		note_type: note.note_type,
+		description: String::new(),
+		beat_time_rel: 0,
+		beat_time: None,
+	};
+
+	let mut cost = distance(request.x, request.y) * REQUEST_DISTANCE_COST
+		+ distance(hand_state.x, hand_state.y) * TRAVEL_COST
+		+ LAYER_COSTS[note.y as usize];
+	if note.cut_direction != request.cut_direction {
+		cost += CHANGED_CUT_COST;
+	}
+	if is_uncomfortable(&swing_transition(&prev_note, note), constraints.gap) {
+		cost += UNCOMFORTABLE_SWING_COST;
+	}
+	cost
+}
+
+/*
+* The requested note if it breaks no hard constraint, otherwise the cheapest cell and cut on the 4x3 grid that doesn't.
+* None if every placement breaks one.
+*/
+pub fn solve_placement(request: &PatternNote, constraints: &PlacementConstraints) -> Option<PatternNote> {
+	if !breaks_hard_constraint(request, constraints) {
+		return Some(request.clone());
+	}
+	let mut best: Option<(PatternNote, f64)> = None;
+	for cut_direction in 0..9 {
+		for y in 0..3 {
+			for x in 0..4 {
+				let note = PatternNote {
+					x,
+					y,
+					cut_direction,
+					description: format!("{},{}", x, y),
+					..request.clone()
+				};
+				if breaks_hard_constraint(&note, constraints) {
+					continue;
+				}
+				let cost = placement_cost(&note, request, constraints);
+				//ties go to the first placement tried so the same request always lands in the same place
+				if best.as_ref().is_none_or(|(_, best_cost)| cost < *best_cost) {
+					best = Some((note, cost));
+				}
+			}
+		}
+	}
+	best.map(|(note, _)| note)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_notes::note;
+
+	fn constraints(hand_state: &HandState) -> PlacementConstraints<'_> {
+		PlacementConstraints {
+			hand_state,
+			other_note: None,
+			gap: 0.5,
+			next_gap: None,
+			vision_window: 0.0,
+		}
+	}
+
+	#[test]
+	fn legal_request_is_kept() {
+		let hand_state = HandState {
+			x: 0,
+			y: 0,
+			cut_direction: 1,
+			swing: None,
+		};
+		//a long fast swing up to the top layer, cheaper closer in but it breaks no hard constraint
+		let request = note(2, 2, 0, 0, 0.0);
+		let placed = solve_placement(&request, &constraints(&hand_state)).unwrap();
+		assert_eq!((placed.x, placed.y, placed.cut_direction), (2, 2, 0));
+	}
+
+	#[test]
+	fn illegal_request_is_moved() {
+		let hand_state = HandState {
+			x: 1,
+			y: 0,
+			cut_direction: 1,
+			swing: None,
+		};
+		//the far column is on the other saber's side
+		let request = note(3, 0, 1, 0, 0.0);
+		let placed = solve_placement(&request, &constraints(&hand_state)).unwrap();
+		assert_eq!((placed.x, placed.y, placed.cut_direction), (2, 0, 1));
+	}
+}
@@ -0,0 +1,347 @@
+use crate::beatmap::{read_difficulty_file, DifficultyFile};
+use crate::flow::{describe_hand_state, pattern_flow, GRID_COLUMNS, SAME_TIME_TOLERANCE};
+use crate::{read_pattern_file, Pattern};
+use glob::glob;
+use std::path::Path;
+
+const GRID_LAYERS: usize = 3;
+
+const CUT_ARROWS: [char; 9] = ['↑', '↓', '←', '→', '↖', '↗', '↙', '↘', '•'];
+const WALL_SHADE: char = '░';
+
+enum GridObject {
+	//note_type 0 is the left (red) saber, 1 is the right (blue) saber, negative picks a hand at placement
+	Note {
+		x: i64,
+		y: i64,
+		note_type: i64,
+		cut_direction: i64,
+	},
+	Bomb {
+		x: i64,
+		y: i64,
+	},
+	//type 0 walls are full height, type 1 walls only cover the top layer
+	Wall {
+		x: i64,
+		width: i64,
+		wall_type: i64,
+	},
+}
+
+struct GridStep {
+	label: String,
+	objects: Vec<GridObject>,
+}
+
+/*
+* cargo run -- preview                      every pattern under src/patterns
+* cargo run -- preview a.json b.json        the given pattern files
+* cargo run -- preview map.dat 8 16         a finished map between two beats
+*/
+pub fn run(args: &[String]) {
+	println!("{}", legend());
+	if args.is_empty() {
+		for entry in glob("src/patterns/**/*.json").expect("Failed to read glob pattern") {
+			match entry {
+				Ok(path) if path.ends_with("manifest.json") => continue,
+				Ok(path) => println!("{}", preview_pattern_file(&path)),
+				Err(e) => println!("{:?}", e),
+			}
+		}
+		return;
+	}
+	let mut arg_id = 0;
+	while arg_id < args.len() {
+		let path = Path::new(&args[arg_id]);
+		if path.extension().is_some_and(|extension| extension == "dat") {
+			let from_beat = parse_beat_arg(args.get(arg_id + 1), 0.0);
+			let to_beat = parse_beat_arg(args.get(arg_id + 2), f64::MAX);
+			let difficulty = read_difficulty_file(path);
+			println!("{}", path.display());
+			println!("{}", render_map(&difficulty, from_beat, to_beat));
+			arg_id += 3;
+		} else {
+			println!("{}", preview_pattern_file(path));
+			arg_id += 1;
+		}
+	}
+}
+
+fn parse_beat_arg(arg: Option<&String>, default: f64) -> f64 {
+	match arg {
+		Some(value) => value.parse::<f64>().expect("Beat range must be a number"),
+		None => default,
+	}
+}
+
+//the preview of every pattern in a file, or why it can't be read
+fn preview_pattern_file(path: &Path) -> String {
+	let patterns = match read_pattern_file(path) {
+		Ok(patterns) => patterns,
+		Err(error) => return error,
+	};
+	let mut output = String::new();
+	for pattern in patterns {
+		let flow = pattern_flow(&pattern);
+		output.push_str(&format!(
+			"{}\nentry L {} | R {}\nexit  L {} | R {}\n{}",
+			path.display(),
+			describe_hand_state(&flow.entry[0]),
+			describe_hand_state(&flow.entry[1]),
+			describe_hand_state(&flow.exit[0]),
+			describe_hand_state(&flow.exit[1]),
+			render_pattern(&pattern)
+		));
+	}
+	output
+}
+
+pub fn legend() -> String {
+	format!(
+		"L = left/red  R = right/blue  ? = either hand  B = bomb  {} = wall  * = overlapping\n\
+		 {} up {} down {} left {} right {} up-left {} up-right {} down-left {} down-right {} dot",
+		WALL_SHADE,
+		CUT_ARROWS[0],
+		CUT_ARROWS[1],
+		CUT_ARROWS[2],
+		CUT_ARROWS[3],
+		CUT_ARROWS[4],
+		CUT_ARROWS[5],
+		CUT_ARROWS[6],
+		CUT_ARROWS[7],
+		CUT_ARROWS[8]
+	)
+}
+
+//pattern notes are timed either by peak offset or by fractional beats, peak steps are drawn first
+#[derive(PartialEq, PartialOrd, Clone, Copy)]
+enum PatternStep {
+	Peak(usize),
+	Beat(f64),
+}
+
+impl PatternStep {
+	fn new(time_rel: usize, time: Option<f64>) -> PatternStep {
+		match time {
+			Some(time) => PatternStep::Beat(time),
+			None => PatternStep::Peak(time_rel),
+		}
+	}
+
+	fn label(self) -> String {
+		match self {
+			PatternStep::Peak(time_rel) => format!("step {}", time_rel),
+			PatternStep::Beat(time) => format!("beat +{}", time),
+		}
+	}
+}
+
+/*
+* Draw every time step of a pattern. Peak offsets don't have a fixed length,
+* so wall durations on them are shown as a number of steps rather than beats.
+*/
+pub fn render_pattern(pattern: &Pattern) -> String {
+	let mut step_times: Vec<PatternStep> = pattern
+		.notes
+		.iter()
+		.map(|note| PatternStep::new(note.beat_time_rel, note.beat_time))
+		.chain(
+			pattern
+				.obstacles
+				.iter()
+				.map(|wall| PatternStep::new(wall.wall_time_rel, wall.wall_time)),
+		)
+		.collect();
+	step_times.sort_by(|a, b| a.partial_cmp(b).unwrap());
+	step_times.dedup();
+
+	let mut steps = Vec::new();
+	for step_time in &step_times {
+		let mut objects = Vec::new();
+		for wall in &pattern.obstacles {
+			let wall_start = PatternStep::new(wall.wall_time_rel, wall.wall_time);
+			let wall_end = match wall_start {
+				_ if wall.duration < 0.0 => step_times
+					.iter()
+					.rev()
+					.find(|step| same_kind(**step, wall_start))
+					.cloned()
+					.unwrap_or(wall_start),
+				PatternStep::Peak(time_rel) => {
+					PatternStep::Peak(time_rel + wall.duration.ceil() as usize)
+				}
+				PatternStep::Beat(time) => PatternStep::Beat(time + wall.duration),
+			};
+			if same_kind(*step_time, wall_start) && wall_start <= *step_time && *step_time <= wall_end
+			{
+				objects.push(GridObject::Wall {
+					x: wall.x,
+					width: wall.width as i64,
+					wall_type: wall.wall_type,
+				});
+			}
+		}
+		for note in pattern
+			.notes
+			.iter()
+			.filter(|note| PatternStep::new(note.beat_time_rel, note.beat_time) == *step_time)
+		{
+			objects.push(grid_note(note.x, note.y, note.note_type, note.cut_direction));
+		}
+		steps.push(GridStep {
+			label: step_time.label(),
+			objects,
+		});
+	}
+	render_steps(&pattern.description, &steps)
+}
+
+fn same_kind(a: PatternStep, b: PatternStep) -> bool {
+	matches!(
+		(a, b),
+		(PatternStep::Peak(_), PatternStep::Peak(_)) | (PatternStep::Beat(_), PatternStep::Beat(_))
+	)
+}
+
+/*
+* Draw every note time of a finished map that falls between from_beat and to_beat (inclusive)
+*/
+pub fn render_map(difficulty: &DifficultyFile, from_beat: f64, to_beat: f64) -> String {
+	let mut step_times: Vec<f64> = difficulty
+		._notes
+		.iter()
+		.map(|note| note._time)
+		.chain(difficulty._obstacles.iter().map(|wall| wall._time))
+		.filter(|time| *time >= from_beat && *time <= to_beat)
+		.collect();
+	step_times.sort_by(|a, b| a.partial_cmp(b).unwrap());
+	step_times.dedup_by(|a, b| (*a - *b).abs() < SAME_TIME_TOLERANCE);
+
+	let mut steps = Vec::new();
+	for step_time in step_times {
+		let mut objects = Vec::new();
+		for wall in &difficulty._obstacles {
+			if wall._time <= step_time + SAME_TIME_TOLERANCE
+				&& step_time < wall._time + wall._duration
+			{
+				objects.push(GridObject::Wall {
+					x: wall._lineIndex,
+					width: wall._width as i64,
+					wall_type: wall._type,
+				});
+			}
+		}
+		for note in &difficulty._notes {
+			if (note._time - step_time).abs() < SAME_TIME_TOLERANCE {
+				objects.push(grid_note(
+					note._lineIndex,
+					note._lineLayer,
+					note._type,
+					note._cutDirection,
+				));
+			}
+		}
+		steps.push(GridStep {
+			label: format!("beat {:.3}", step_time),
+			objects,
+		});
+	}
+	render_steps(&format!("beats {} to {}", from_beat, to_beat), &steps)
+}
+
+fn grid_note(x: i64, y: i64, note_type: i64, cut_direction: i64) -> GridObject {
+	if note_type == 3 {
+		GridObject::Bomb { x, y }
+	} else {
+		GridObject::Note {
+			x,
+			y,
+			note_type,
+			cut_direction,
+		}
+	}
+}
+
+fn render_steps(title: &str, steps: &[GridStep]) -> String {
+	let mut output = format!("== {} ==\n", title);
+	for step in steps {
+		output.push_str(&render_step(step));
+	}
+	output
+}
+
+fn render_step(step: &GridStep) -> String {
+	//each cell is 3 characters wide, layer 0 is the bottom row
+	let mut cells = [[[' '; 3]; GRID_COLUMNS as usize]; GRID_LAYERS];
+	let mut off_grid = 0;
+
+	for object in &step.objects {
+		if let GridObject::Wall { x, width, wall_type } = object {
+			for column in *x..(*x + *width) {
+				if !on_grid(column, 0) {
+					continue;
+				}
+				let lowest_layer = if *wall_type == 1 { GRID_LAYERS - 1 } else { 0 };
+				for row in cells.iter_mut().skip(lowest_layer) {
+					row[column as usize] = [WALL_SHADE; 3];
+				}
+			}
+		}
+	}
+	for object in &step.objects {
+		let (x, y, mark) = match object {
+			GridObject::Note {
+				x,
+				y,
+				note_type,
+				cut_direction,
+			} => {
+				let hand = match note_type {
+					0 => 'L',
+					1 => 'R',
+					_ => '?',
+				};
+				let arrow = CUT_ARROWS
+					.get(*cut_direction as usize)
+					.cloned()
+					.unwrap_or('•');
+				(*x, *y, [hand, arrow])
+			}
+			GridObject::Bomb { x, y } => (*x, *y, [' ', 'B']),
+			GridObject::Wall { .. } => continue,
+		};
+		if !on_grid(x, y) {
+			off_grid += 1;
+			continue;
+		}
+		let cell = &mut cells[y as usize][x as usize];
+		if cell[1] != ' ' && cell[1] != WALL_SHADE {
+			cell[2] = '*';
+			continue;
+		}
+		cell[0] = mark[0];
+		cell[1] = mark[1];
+	}
+
+	let border = "+---".repeat(GRID_COLUMNS as usize) + "+\n";
+	let mut output = step.label.to_owned();
+	if off_grid > 0 {
+		output.push_str(&format!(" ({} off grid)", off_grid));
+	}
+	output.push('\n');
+	output.push_str(&border);
+	for row in cells.iter().rev() {
+		for cell in row.iter() {
+			output.push('|');
+			output.extend(cell.iter());
+		}
+		output.push_str("|\n");
+	}
+	output.push_str(&border);
+	output
+}
+
+fn on_grid(x: i64, y: i64) -> bool {
+	x >= 0 && (x as usize) < GRID_COLUMNS as usize && y >= 0 && (y as usize) < GRID_LAYERS
+}
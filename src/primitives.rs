@@ -0,0 +1,312 @@
+use crate::flow::{HandState, Swing};
+use crate::{Pattern, PatternNote, PatternSource};
+use rand::rngs::StdRng;
+use rand::Rng;
+
+const CUT_UP: i64 = 0;
+const CUT_DOWN: i64 = 1;
+const CUT_DOT: i64 = 8;
+//time (in beats) between the notes of a slider, as close as one saber can go without them counting as too fast
+const SLIDER_SPACING: f64 = 0.125;
+//peaks closer together than this (in beats) are fast enough for streams and sliders, and up to the slow limit for jumps
+const FAST_STEP_BEATS: f64 = 0.5;
+const SLOW_STEP_BEATS: f64 = 1.0;
+
+/*
+* Well known shapes:
+* stream, the sabers take turns on single notes,
+* jump, wide jump and crossover jump, both sabers swing together in the center columns, the outside columns or crossed over,
+* stack, tower and window, one saber cuts two or three notes in a column in one swing, a window has a gap in the middle,
+* slider, an arrow followed by dots down or up the column for one long swing
+*/
+#[derive(Clone, Copy, PartialEq)]
+pub enum Primitive {
+	Stream,
+	Jump,
+	WideJump,
+	CrossoverJump,
+	Stack,
+	Tower,
+	Window,
+	Slider,
+}
+
+impl Primitive {
+	pub fn name(self) -> &'static str {
+		match self {
+			Primitive::Stream => "stream",
+			Primitive::Jump => "jump",
+			Primitive::WideJump => "wide jump",
+			Primitive::CrossoverJump => "crossover jump",
+			Primitive::Stack => "stack",
+			Primitive::Tower => "tower",
+			Primitive::Window => "window",
+			Primitive::Slider => "slider",
+		}
+	}
+}
+
+/*
+* Where the sabers are before the pattern, how many swings it makes and how far apart they are (in beats)
+*/
+pub struct PrimitiveParams {
+	pub hand_states: [HandState; 2],
+	pub steps: usize,
+	pub step_beats: f64,
+}
+
+//a saber's first swing goes the other way to its last one, forehand if it hasn't swung yet
+fn first_swing(hand_state: &HandState) -> Swing {
+	match hand_state.swing {
+		Some(swing) => swing.flipped(),
+		None => Swing::Forehand,
+	}
+}
+
+//forehands cut down and backhands cut up, for both sabers
+fn vertical_cut(swing: Swing) -> i64 {
+	match swing {
+		Swing::Forehand => CUT_DOWN,
+		Swing::Backhand => CUT_UP,
+	}
+}
+
+fn primitive_note(x: i64, y: i64, cut_direction: i64, note_type: usize, beat_time: f64, primitive: Primitive) -> PatternNote {
+	PatternNote {
+		x,
+		y,
+		cut_direction,
+		note_type: note_type as i64,
+		description: primitive.name().to_owned(),
+		beat_time_rel: 0,
+		beat_time: Some(beat_time),
+	}
+}
+
+/*
+* Build a primitive as a pattern timed in beats from its start. Each saber alternates forehand and backhand,
+* starting the other way to its last swing, so the pattern flows on from whatever came before it.
+*/
+pub fn generate_primitive(primitive: Primitive, params: &PrimitiveParams) -> Pattern {
+	let mut swings = [first_swing(&params.hand_states[0]), first_swing(&params.hand_states[1])];
+	let mut notes = Vec::new();
+	for step in 0..params.steps {
+		let time = step as f64 * params.step_beats;
+		//the sabers take turns on everything but jumps
+		let hand = step % 2;
+		match primitive {
+			Primitive::Stream => {
+				let x = [1, 2][hand];
+				notes.push(primitive_note(x, 0, vertical_cut(swings[hand]), hand, time, primitive));
+				swings[hand] = swings[hand].flipped();
+			}
+			Primitive::Jump | Primitive::WideJump | Primitive::CrossoverJump => {
+				let columns = match primitive {
+					Primitive::Jump => [1, 2],
+					Primitive::WideJump => [0, 3],
+					_ => [2, 1],
+				};
+				for (hand, x) in columns.iter().enumerate() {
+					notes.push(primitive_note(*x, 0, vertical_cut(swings[hand]), hand, time, primitive));
+					swings[hand] = swings[hand].flipped();
+				}
+			}
+			Primitive::Stack | Primitive::Tower | Primitive::Window => {
+				let layers: &[i64] = match primitive {
+					Primitive::Stack => &[0, 1],
+					Primitive::Tower => &[0, 1, 2],
+					_ => &[0, 2],
+				};
+				//outside columns, so the middle layer doesn't block the player's view
+				let x = [0, 3][hand];
+				for y in layers {
+					notes.push(primitive_note(x, *y, vertical_cut(swings[hand]), hand, time, primitive));
+				}
+				swings[hand] = swings[hand].flipped();
+			}
+			Primitive::Slider => {
+				let x = [0, 3][hand];
+				let cut_direction = vertical_cut(swings[hand]);
+				let layers = if cut_direction == CUT_DOWN { [2, 1, 0] } else { [0, 1, 2] };
+				//an even number of dots after the arrow leaves the saber on the same swing as the arrow
+				for (slide_id, y) in layers.iter().enumerate() {
+					let slide_cut = if slide_id == 0 { cut_direction } else { CUT_DOT };
+					let slide_time = time + slide_id as f64 * SLIDER_SPACING;
+					notes.push(primitive_note(x, *y, slide_cut, hand, slide_time, primitive));
+				}
+				swings[hand] = swings[hand].flipped();
+			}
+		}
+	}
+
+	Pattern {
+		id: format!("primitive/{}", primitive.name()),
+		template_id: None,
+		source: PatternSource::Primitive,
+		description: primitive.name().to_owned(),
+		notes,
+		obstacles: Vec::new(),
+	}
+}
+
+/*
+* Pick a primitive that suits the music: streams and sliders for fast peaks, jumps in between,
+* stacks, windows and towers for slow ones. Loud peaks get the bigger shapes of each group.
+*/
+pub fn choose_primitive(rng: &mut StdRng, step_beats: f64, loud: bool, crossovers_allowed: bool) -> Primitive {
+	let group: Vec<Primitive> = if step_beats <= FAST_STEP_BEATS {
+		vec![Primitive::Stream, Primitive::Slider]
+	} else if step_beats <= SLOW_STEP_BEATS {
+		let mut jumps = vec![Primitive::Jump, Primitive::WideJump];
+		if crossovers_allowed {
+			jumps.push(Primitive::CrossoverJump);
+		}
+		jumps
+	} else {
+		vec![Primitive::Stack, Primitive::Window, Primitive::Tower]
+	};
+	let half = group.len() / 2;
+	let (start, end) = if loud { (half, group.len()) } else { (0, group.len() - half) };
+	group[rng.gen_range(start, end)]
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::flow::note_order;
+	use crate::parity::find_parity_breaks;
+
+	const ALL_PRIMITIVES: [Primitive; 8] = [
+		Primitive::Stream,
+		Primitive::Jump,
+		Primitive::WideJump,
+		Primitive::CrossoverJump,
+		Primitive::Stack,
+		Primitive::Tower,
+		Primitive::Window,
+		Primitive::Slider,
+	];
+
+	fn params(swing: Option<Swing>, steps: usize) -> PrimitiveParams {
+		let hand_state = HandState {
+			x: 1,
+			y: 0,
+			cut_direction: CUT_DOWN,
+			swing,
+		};
+		PrimitiveParams {
+			hand_states: [hand_state, hand_state],
+			steps,
+			step_beats: 0.5,
+		}
+	}
+
+	//the notes of one step, by the time the step starts
+	fn step_notes(pattern: &Pattern, step: usize) -> Vec<&PatternNote> {
+		let start = step as f64 * 0.5;
+		pattern
+			.notes
+			.iter()
+			.filter(|note| note_order(note) >= start && note_order(note) < start + 0.5)
+			.collect()
+	}
+
+	#[test]
+	fn notes_per_step() {
+		let expected = [1, 2, 2, 2, 2, 3, 2, 3];
+		for (primitive, notes_per_step) in ALL_PRIMITIVES.iter().zip(expected.iter()) {
+			let pattern = generate_primitive(*primitive, &params(None, 4));
+			assert_eq!(pattern.notes.len(), 4 * notes_per_step, "{}", primitive.name());
+			for step in 0..4 {
+				assert_eq!(step_notes(&pattern, step).len(), *notes_per_step, "{}", primitive.name());
+			}
+		}
+	}
+
+	#[test]
+	fn notes_are_on_the_grid() {
+		for primitive in ALL_PRIMITIVES.iter() {
+			for note in generate_primitive(*primitive, &params(None, 4)).notes {
+				assert!((0..4).contains(&note.x) && (0..3).contains(&note.y), "{}", primitive.name());
+				assert!(note.note_type == 0 || note.note_type == 1, "{}", primitive.name());
+			}
+		}
+	}
+
+	#[test]
+	fn stream_and_column_shapes_take_turns() {
+		let column_shapes = [
+			Primitive::Stream,
+			Primitive::Stack,
+			Primitive::Tower,
+			Primitive::Window,
+			Primitive::Slider,
+		];
+		for primitive in column_shapes.iter() {
+			let pattern = generate_primitive(*primitive, &params(None, 4));
+			for step in 0..4 {
+				let notes = step_notes(&pattern, step);
+				assert!(notes.iter().all(|note| note.note_type == (step % 2) as i64), "{}", primitive.name());
+				assert!(notes.iter().all(|note| note.x == notes[0].x), "{}", primitive.name());
+			}
+		}
+	}
+
+	#[test]
+	fn jumps_swing_both_sabers_together() {
+		let columns = [(Primitive::Jump, [1, 2]), (Primitive::WideJump, [0, 3]), (Primitive::CrossoverJump, [2, 1])];
+		for (primitive, columns) in columns.iter() {
+			let pattern = generate_primitive(*primitive, &params(None, 2));
+			for step in 0..2 {
+				let notes = step_notes(&pattern, step);
+				assert_eq!(notes[0].note_type, 0);
+				assert_eq!(notes[1].note_type, 1);
+				assert_eq!([notes[0].x, notes[1].x], *columns, "{}", primitive.name());
+				assert_eq!(note_order(notes[0]), note_order(notes[1]));
+			}
+		}
+	}
+
+	#[test]
+	fn tower_and_window_layers() {
+		let tower = generate_primitive(Primitive::Tower, &params(None, 1));
+		let layers: Vec<i64> = tower.notes.iter().map(|note| note.y).collect();
+		assert_eq!(layers, vec![0, 1, 2]);
+		let window = generate_primitive(Primitive::Window, &params(None, 1));
+		let layers: Vec<i64> = window.notes.iter().map(|note| note.y).collect();
+		assert_eq!(layers, vec![0, 2]);
+	}
+
+	#[test]
+	fn slider_is_an_arrow_then_dots() {
+		let pattern = generate_primitive(Primitive::Slider, &params(None, 2));
+		for step in 0..2 {
+			let notes = step_notes(&pattern, step);
+			assert_ne!(notes[0].cut_direction, CUT_DOT);
+			assert!(notes[1..].iter().all(|note| note.cut_direction == CUT_DOT));
+			assert_eq!(note_order(notes[1]) - note_order(notes[0]), SLIDER_SPACING);
+		}
+	}
+
+	#[test]
+	fn primitives_keep_parity() {
+		for swing in [None, Some(Swing::Forehand), Some(Swing::Backhand)].iter() {
+			for primitive in ALL_PRIMITIVES.iter() {
+				let pattern = generate_primitive(*primitive, &params(*swing, 6));
+				assert!(find_parity_breaks(&pattern.notes, None).is_empty(), "{}", primitive.name());
+			}
+		}
+	}
+
+	#[test]
+	fn first_swing_follows_the_last_one() {
+		for primitive in ALL_PRIMITIVES.iter() {
+			let after_forehand = generate_primitive(*primitive, &params(Some(Swing::Forehand), 2));
+			assert_eq!(after_forehand.notes[0].cut_direction, CUT_UP, "{}", primitive.name());
+			let after_backhand = generate_primitive(*primitive, &params(Some(Swing::Backhand), 2));
+			assert_eq!(after_backhand.notes[0].cut_direction, CUT_DOWN, "{}", primitive.name());
+			let first_pattern = generate_primitive(*primitive, &params(None, 2));
+			assert_eq!(first_pattern.notes[0].cut_direction, CUT_DOWN, "{}", primitive.name());
+		}
+	}
+}
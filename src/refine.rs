@@ -0,0 +1,427 @@
+use crate::bombs::NOTE_TYPE_BOMB;
+use crate::density::section_densities;
+use crate::drops::is_thinned_out;
+use crate::flow::{note_order, opposite_cut, SAME_TIME_TOLERANCE};
+use crate::settings::RefineSettings;
+use crate::swing_rate::breaks_swing_rate;
+use crate::validate::validate_notes;
+use crate::{
+	add_pattern, create_note_json, create_wall_json, is_drop_peak, pattern_anchor_in_beats, pattern_pool, MapContext,
+	MapState, PatternNote, PatternWall,
+};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+//notes on one hand closer together than this (in beats) are too fast to swing, unless they're cut together,
+//for a difficulty without swing limits
+const MIN_SWING_GAP: f64 = 0.125;
+//how much each problem adds to a candidate's penalty
+const ISSUE_PENALTY: f64 = 2.0;
+const SPACING_PENALTY: f64 = 1.0;
+//for every note per second away from the target, on average over the sections
+const NPS_PENALTY: f64 = 10.0;
+//the best candidates always go through to the next generation unchanged
+const ELITE_CANDIDATES: usize = 2;
+
+/*
+* How playable a candidate map is, from the validation rules, notes that break the swing limits
+* and how far its notes per second are from the density plan
+*/
+#[derive(Clone, Default, Serialize)]
+pub struct Fitness {
+	//issues found by each validation rule
+	pub issues: BTreeMap<&'static str, usize>,
+	//notes that break the difficulty's swing limits
+	pub spacing_issues: usize,
+	pub notes_per_second: f64,
+	//how far the notes per second are from the target, on average over the sections
+	pub nps_distance: f64,
+	//lower is better
+	pub penalty: f64,
+}
+
+#[derive(Serialize)]
+struct GenerationFitness {
+	generation: usize,
+	best: Fitness,
+	average_penalty: f64,
+}
+
+/*
+* What a candidate map is made of, the walls go along with the notes of the patterns they came with
+*/
+#[derive(Clone)]
+struct Genome {
+	notes: Vec<PatternNote>,
+	walls: Vec<PatternWall>,
+}
+
+#[derive(Clone)]
+struct Candidate {
+	genome: Genome,
+	fitness: Fitness,
+}
+
+impl Candidate {
+	fn new(mut genome: Genome, context: &MapContext, settings: &RefineSettings) -> Candidate {
+		sort_notes(&mut genome.notes);
+		let fitness = map_fitness(&genome.notes, context, settings);
+		Candidate { genome, fitness }
+	}
+}
+
+fn sort_notes(notes: &mut [PatternNote]) {
+	notes.sort_by(|a, b| note_order(a).partial_cmp(&note_order(b)).unwrap());
+}
+
+/*
+* Notes per second from the first note to the last, bombs don't count
+*/
+pub fn notes_per_second(notes: &[PatternNote], beats_per_minute: f64) -> f64 {
+	let times: Vec<f64> = notes
+		.iter()
+		.filter(|note| note.note_type != NOTE_TYPE_BOMB)
+		.map(note_order)
+		.collect();
+	let first_time = times.iter().cloned().fold(f64::INFINITY, f64::min);
+	let last_time = times.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+	let duration_sec = ((last_time - first_time) / beats_per_minute) * 60.0;
+	if times.len() < 2 || duration_sec <= 0.0 {
+		return 0.0;
+	}
+	times.len() as f64 / duration_sec
+}
+
+/*
+* How far the notes per second of each section of the density plan are from its target, on average.
+* A difficulty without a plan is held to target_nps over the whole map.
+*/
+fn nps_distance(notes: &[PatternNote], context: &MapContext, settings: &RefineSettings) -> f64 {
+	let beats_per_minute = context.beats_per_minute;
+	match context.density_plan {
+		Some(plan) => {
+			let end_in_beats = context
+				.beats
+				.last()
+				.map_or(0.0, |beat| (beat.peak_time_sec / 60.0) * beats_per_minute);
+			let sections = section_densities(plan, notes, end_in_beats, beats_per_minute);
+			let total_distance: f64 = sections
+				.iter()
+				.map(|section| (section.achieved_nps - section.target_nps).abs())
+				.sum();
+			total_distance / sections.len().max(1) as f64
+		}
+		None => (notes_per_second(notes, beats_per_minute) - settings.target_nps).abs(),
+	}
+}
+
+/*
+* How many notes break the difficulty's swing limits, each checked against the notes around it.
+* Notes should be in order.
+*/
+fn swing_rate_issues(notes: &[PatternNote], context: &MapContext) -> usize {
+	let limit = match context.settings.swing_limit() {
+		Some(limit) => limit,
+		None => return spacing_issues(notes),
+	};
+	let window_seconds = context.settings.swing_limits.window_seconds;
+	let lookback = (window_seconds.max(limit.min_gap_seconds) / 60.0) * context.beats_per_minute;
+	let mut first_around = 0;
+	notes
+		.iter()
+		.enumerate()
+		.filter(|(note_id, note)| {
+			let time = note_order(note);
+			while note_order(&notes[first_around]) < time - lookback {
+				first_around += 1;
+			}
+			let around: Vec<&PatternNote> = notes[first_around..]
+				.iter()
+				.enumerate()
+				.take_while(|(_, other)| note_order(other) <= time + lookback)
+				.filter(|(other_id, _)| first_around + other_id != *note_id)
+				.map(|(_, other)| other)
+				.collect();
+			note.note_type != NOTE_TYPE_BOMB
+				&& breaks_swing_rate(&around, note, limit, window_seconds, context.beats_per_minute)
+		})
+		.count()
+}
+
+//notes on one hand too close to the one before it
+fn spacing_issues(notes: &[PatternNote]) -> usize {
+	[0, 1]
+		.iter()
+		.map(|hand| {
+			let mut times: Vec<f64> = notes
+				.iter()
+				.filter(|note| note.note_type == *hand)
+				.map(note_order)
+				.collect();
+			times.sort_by(|a, b| a.partial_cmp(b).unwrap());
+			times
+				.windows(2)
+				.filter(|pair| {
+					let gap = pair[1] - pair[0];
+					gap > SAME_TIME_TOLERANCE && gap < MIN_SWING_GAP
+				})
+				.count()
+		})
+		.sum()
+}
+
+pub fn map_fitness(notes: &[PatternNote], context: &MapContext, settings: &RefineSettings) -> Fitness {
+	let mut issues = BTreeMap::new();
+	for issue in validate_notes(notes, context.settings, context.vision_window) {
+		*issues.entry(issue.rule).or_insert(0) += 1;
+	}
+	let spacing_issues = swing_rate_issues(notes, context);
+	let notes_per_second = notes_per_second(notes, context.beats_per_minute);
+	let nps_distance = nps_distance(notes, context, settings);
+
+	let issue_count: usize = issues.values().sum();
+	let penalty = issue_count as f64 * ISSUE_PENALTY
+		+ spacing_issues as f64 * SPACING_PENALTY
+		+ nps_distance * NPS_PENALTY;
+	Fitness {
+		issues,
+		spacing_issues,
+		notes_per_second,
+		nps_distance,
+		penalty,
+	}
+}
+
+/*
+* Move notes a cell up, down, left or right, or turn them, then maybe swap a pattern for another from the library
+*/
+fn mutate(genome: &mut Genome, rng: &mut StdRng, context: &MapContext, settings: &RefineSettings) {
+	for note in genome.notes.iter_mut() {
+		if rng.gen::<f64>() >= settings.mutation_rate {
+			continue;
+		}
+		let step = if rng.gen::<bool>() { 1 } else { -1 };
+		match rng.gen_range(0, 3) {
+			0 => note.x = (note.x + step).clamp(0, 3),
+			1 => note.y = (note.y + step).clamp(0, 2),
+			_ if note.note_type != NOTE_TYPE_BOMB => {
+				//turning a note round is the usual fix for a parity break
+				note.cut_direction = if rng.gen::<bool>() {
+					opposite_cut(note.cut_direction)
+				} else {
+					rng.gen_range(0, 9)
+				};
+			}
+			_ => {}
+		}
+	}
+	if rng.gen::<f64>() < settings.pattern_swap_rate {
+		swap_pattern(genome, rng, context);
+	}
+}
+
+/*
+* Place a random library pattern on a random peak and replace whatever notes and walls start while it plays
+*/
+fn swap_pattern(genome: &mut Genome, rng: &mut StdRng, context: &MapContext) {
+	let beat_id = rng.gen_range(0, context.beats.len());
+	let beat = &context.beats[beat_id];
+	let patterns = pattern_pool(context, beat_id);
+	if beat.pitch <= context.lowest_threshold || patterns.is_empty() {
+		return;
+	}
+	//drops and the thin out before them keep what they were given
+	let thinned_out = context.drop_response.is_some()
+		&& is_thinned_out(context.drops, context.thin_out_seconds, beat.peak_time_sec);
+	if thinned_out || is_drop_peak(context, beat_id) {
+		return;
+	}
+	let pattern = &patterns[rng.gen_range(0, patterns.len())];
+
+	//place it on an empty map to find out where its notes land
+	let quiet_context = MapContext { log: false, ..*context };
+	let mut placed = MapState::new(StdRng::seed_from_u64(rng.gen()), String::new());
+	add_pattern(&mut placed, &quiet_context, pattern, beat_id);
+	let times: Vec<f64> = placed.processed_notes.iter().map(note_order).collect();
+	if times.is_empty() {
+		return;
+	}
+	//the same start the pattern gets when it's generated
+	let start_time = pattern_anchor_in_beats(context.beats, beat_id, context.beats_per_minute);
+	let end_time = times.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+
+	let outside = |time: f64| time < start_time - SAME_TIME_TOLERANCE || time > end_time + SAME_TIME_TOLERANCE;
+	genome.notes.retain(|note| outside(note_order(note)));
+	genome.notes.extend(placed.processed_notes);
+	sort_notes(&mut genome.notes);
+	genome.walls.retain(|wall| outside(wall.wall_time.unwrap_or(0.0)));
+	genome.walls.extend(placed.processed_walls);
+}
+
+/*
+* The notes and walls of the first parent up to a random beat and those of the second from then on
+*/
+fn crossover(first: &Genome, second: &Genome, rng: &mut StdRng) -> Genome {
+	let end_time = first.notes.last().map(note_order).unwrap_or(0.0).max(1.0);
+	let cut_time = rng.gen_range(0.0, end_time).floor();
+	let wall_time = |wall: &&PatternWall| wall.wall_time.unwrap_or(0.0);
+	Genome {
+		notes: first
+			.notes
+			.iter()
+			.filter(|note| note_order(note) < cut_time)
+			.chain(second.notes.iter().filter(|note| note_order(note) >= cut_time))
+			.cloned()
+			.collect(),
+		walls: first
+			.walls
+			.iter()
+			.filter(|wall| wall_time(wall) < cut_time)
+			.chain(second.walls.iter().filter(|wall| wall_time(wall) >= cut_time))
+			.cloned()
+			.collect(),
+	}
+}
+
+//the better of two random candidates
+fn tournament<'c>(population: &'c [Candidate], rng: &mut StdRng) -> &'c Candidate {
+	let first = &population[rng.gen_range(0, population.len())];
+	let second = &population[rng.gen_range(0, population.len())];
+	if second.fitness.penalty < first.fitness.penalty {
+		second
+	} else {
+		first
+	}
+}
+
+fn sort_population(population: &mut [Candidate]) {
+	population.sort_by(|a, b| a.fitness.penalty.partial_cmp(&b.fitness.penalty).unwrap());
+}
+
+fn generation_fitness(generation: usize, population: &[Candidate]) -> GenerationFitness {
+	let total_penalty: f64 = population.iter().map(|candidate| candidate.fitness.penalty).sum();
+	GenerationFitness {
+		generation,
+		best: population[0].fitness.clone(),
+		average_penalty: total_penalty / population.len() as f64,
+	}
+}
+
+/*
+* Rebuild the note json of a map from its notes, each note is known by the peak it falls on or after
+*/
+fn notes_json(notes: &[PatternNote], context: &MapContext) -> String {
+	notes
+		.iter()
+		.map(|note| {
+			let time_in_beats = note_order(note);
+			let time_sec = (time_in_beats / context.beats_per_minute) * 60.0;
+			let beat_id = context
+				.beats
+				.iter()
+				.rposition(|beat| beat.peak_time_sec <= time_sec)
+				.unwrap_or(0);
+			create_note_json(
+				beat_id,
+				time_sec,
+				1600.0,
+				time_in_beats,
+				note.x,
+				note.y,
+				note.note_type,
+				note.cut_direction,
+				note.description.to_owned(),
+			)
+		})
+		.collect()
+}
+
+/*
+* Evolve the notes and walls of a finished map: every generation keeps the best candidates and fills up with children
+* made by crossing over and mutating the better half. The map's rng seeds it all so the same seed refines the same way.
+* The best map replaces the generated one, header is the start of the map json before the notes.
+* The best and average penalty of every generation are written to history if there is one.
+*/
+pub fn refine_map(
+	state: &mut MapState,
+	context: &MapContext,
+	header: &str,
+	settings: &RefineSettings,
+	history_path: Option<&str>,
+) -> Result<(), String> {
+	let mut rng = StdRng::seed_from_u64(state.rng.gen());
+	let population_size = settings.population.max(1);
+
+	//the first generation is the generated map and mutated copies of it
+	let generated = Candidate::new(
+		Genome {
+			notes: state.processed_notes.clone(),
+			walls: state.processed_walls.clone(),
+		},
+		context,
+		settings,
+	);
+	let start_penalty = generated.fitness.penalty;
+	let mut population = vec![generated];
+	while population.len() < population_size {
+		let mut genome = population[0].genome.clone();
+		mutate(&mut genome, &mut rng, context, settings);
+		population.push(Candidate::new(genome, context, settings));
+	}
+	sort_population(&mut population);
+	let mut history = vec![generation_fitness(0, &population)];
+
+	for generation in 1..=settings.generations {
+		let mut next_population: Vec<Candidate> =
+			population.iter().take(ELITE_CANDIDATES).cloned().collect();
+		while next_population.len() < population_size {
+			let first = tournament(&population, &mut rng);
+			let mut genome = if rng.gen::<f64>() < settings.crossover_rate {
+				let second = tournament(&population, &mut rng);
+				crossover(&first.genome, &second.genome, &mut rng)
+			} else {
+				first.genome.clone()
+			};
+			mutate(&mut genome, &mut rng, context, settings);
+			next_population.push(Candidate::new(genome, context, settings));
+		}
+		sort_population(&mut next_population);
+		population = next_population;
+		history.push(generation_fitness(generation, &population));
+	}
+
+	if let Some(history_path) = history_path {
+		write_history(&history, history_path)?;
+	}
+
+	let best = population.swap_remove(0);
+	println!(
+		"Refined for {} generations, penalty {:.1} -> {:.1}",
+		settings.generations, start_penalty, best.fitness.penalty
+	);
+	state.contents = format!("{}{}", header, notes_json(&best.genome.notes, context));
+	state.obstacles_content = format!(" {}", best.genome.walls.iter().map(create_wall_json).collect::<String>());
+	state.processed_notes = best.genome.notes;
+	state.processed_walls = best.genome.walls;
+	state.stats.refine_generations = settings.generations;
+	state.stats.refine_start_penalty = start_penalty;
+	state.stats.refine_penalty = best.fitness.penalty;
+	Ok(())
+}
+
+fn write_history(history: &[GenerationFitness], history_path: &str) -> Result<(), String> {
+	let history_json = serde_json::to_string_pretty(history)
+		.map_err(|error| format!("Could not convert fitness history to json: {}", error))?;
+	if let Some(history_folder) = Path::new(history_path).parent() {
+		fs::create_dir_all(history_folder)
+			.map_err(|error| format!("Could not create fitness history folder {}: {}", history_folder.display(), error))?;
+	}
+	fs::write(history_path, history_json)
+		.map_err(|error| format!("Could not write fitness history {}: {}", history_path, error))?;
+	println!("Fitness history written to {}", history_path);
+	Ok(())
+}
@@ -0,0 +1,147 @@
+use crate::report::GenerationStats;
+use crate::settings::RepetitionSettings;
+use crate::template::template_base_id;
+use crate::{Pattern, PatternSource};
+use rand::rngs::StdRng;
+use rand::Rng;
+use std::collections::{HashMap, VecDeque};
+
+//the share rule only starts once this many patterns have been placed, before that every pick is a big share
+const MIN_PATTERNS_FOR_SHARE: usize = 10;
+
+/*
+* Remembers which patterns have been placed so picks can follow the repetition settings.
+* Every pattern expanded from a template counts as the template.
+*/
+#[derive(Clone)]
+pub struct RepetitionTracker {
+	recent: VecDeque<String>,
+	uses: HashMap<String, usize>,
+	placed: usize,
+}
+
+impl RepetitionTracker {
+	pub fn new() -> RepetitionTracker {
+		RepetitionTracker {
+			recent: VecDeque::new(),
+			uses: HashMap::new(),
+			placed: 0,
+		}
+	}
+
+	pub fn on_cooldown(&self, pattern: &Pattern) -> bool {
+		let pattern_id = template_base_id(pattern);
+		self.recent.iter().any(|recent_id| recent_id == pattern_id)
+	}
+
+	pub fn over_share(&self, settings: &RepetitionSettings, pattern: &Pattern) -> bool {
+		if self.placed < MIN_PATTERNS_FOR_SHARE {
+			return false;
+		}
+		let uses = self.uses.get(template_base_id(pattern)).cloned().unwrap_or(0);
+		(uses + 1) as f64 > settings.max_share * (self.placed + 1) as f64
+	}
+
+	/*
+	* How many pairs of placed library patterns are the same pattern, placing one that's been used n times before adds n
+	*/
+	pub fn repeated_pairs(&self) -> usize {
+		self.uses.values().map(|uses| uses * (uses - 1) / 2).sum()
+	}
+
+	/*
+	* Record a placed pattern, patterns that aren't from the library count towards the total for the max share
+	* but not the cooldown or the uses
+	*/
+	pub fn record(&mut self, settings: &RepetitionSettings, pattern: &Pattern) {
+		self.placed += 1;
+		if pattern.source != PatternSource::Library {
+			return;
+		}
+		let pattern_id = template_base_id(pattern);
+		*self.uses.entry(pattern_id.to_owned()).or_insert(0) += 1;
+		self.recent.push_back(pattern_id.to_owned());
+		while self.recent.len() > settings.cooldown_patterns {
+			self.recent.pop_front();
+		}
+	}
+}
+
+/*
+* A random candidate, a template is as likely as any other pattern however many patterns it expands to.
+* None when there are no candidates.
+*/
+fn pick_candidate<'a>(rng: &mut StdRng, candidates: &[&'a Pattern]) -> Option<&'a Pattern> {
+	if candidates.is_empty() {
+		return None;
+	}
+	let mut base_ids: Vec<&str> = candidates.iter().map(|pattern| template_base_id(pattern)).collect();
+	base_ids.sort_unstable();
+	base_ids.dedup();
+	let base_id = base_ids[rng.gen_range(0, base_ids.len())];
+	let variants: Vec<&Pattern> = candidates
+		.iter()
+		.filter(|pattern| template_base_id(pattern) == base_id)
+		.cloned()
+		.collect();
+	Some(variants[rng.gen_range(0, variants.len())])
+}
+
+/*
+* Pick a random candidate, then re-pick if it breaks the cooldown or the max share.
+* A rule that every candidate breaks is ignored for this pick, and there's no pick without candidates.
+*/
+pub fn choose_pattern<'a>(
+	rng: &mut StdRng,
+	candidates: &[&'a Pattern],
+	tracker: &RepetitionTracker,
+	settings: &RepetitionSettings,
+	stats: &mut GenerationStats,
+) -> Option<&'a Pattern> {
+	let mut pick = pick_candidate(rng, candidates)?;
+
+	if tracker.on_cooldown(pick) {
+		let allowed: Vec<&Pattern> = candidates
+			.iter()
+			.filter(|pattern| !tracker.on_cooldown(pattern))
+			.cloned()
+			.collect();
+		if let Some(allowed_pick) = pick_candidate(rng, &allowed) {
+			pick = allowed_pick;
+			stats.cooldown_changes += 1;
+		}
+	}
+	if tracker.over_share(settings, pick) {
+		let allowed: Vec<&Pattern> = candidates
+			.iter()
+			.filter(|pattern| {
+				!tracker.on_cooldown(pattern) && !tracker.over_share(settings, pattern)
+			})
+			.cloned()
+			.collect();
+		if let Some(allowed_pick) = pick_candidate(rng, &allowed) {
+			pick = allowed_pick;
+			stats.max_share_changes += 1;
+		}
+	}
+	Some(pick)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use rand::SeedableRng;
+
+	#[test]
+	fn no_candidates_no_pick() {
+		let mut rng = StdRng::seed_from_u64(1);
+		let pick = choose_pattern(
+			&mut rng,
+			&[],
+			&RepetitionTracker::new(),
+			&RepetitionSettings::default(),
+			&mut GenerationStats::default(),
+		);
+		assert!(pick.is_none());
+	}
+}
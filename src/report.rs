@@ -0,0 +1,491 @@
+use crate::density::SectionDensity;
+use crate::hands::find_hand_issues;
+use crate::library::load_library;
+use crate::parity::find_parity_breaks;
+use crate::settings::get_generator_settings;
+use crate::swing::{summarize_swings, SwingSummary};
+use crate::template::template_base_id;
+use crate::vision::find_vision_blocks;
+use crate::{build_bsaber_map, get_seed, get_vision_window_beats, Pattern, PatternNote};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+const CUT_NAMES: [&str; 9] = [
+	"up",
+	"down",
+	"left",
+	"right",
+	"up-left",
+	"up-right",
+	"down-left",
+	"down-right",
+	"dot",
+];
+
+/*
+* What the generator did during one run, filled in by generate_map
+*/
+#[derive(Clone, Default, Serialize)]
+pub struct GenerationStats {
+	//how many times each library pattern was picked, by pattern id, or template id for expanded templates
+	pub pattern_usage: BTreeMap<String, usize>,
+	//patterns placed again on repeated phrases and as drop signatures, by the same ids, dynamic and primitive ones included
+	pub phrase_repeat_usage: BTreeMap<String, usize>,
+	pub drop_usage: BTreeMap<String, usize>,
+	//how many library patterns came from each pool
+	pub pool_usage: BTreeMap<String, usize>,
+	pub dynamic_patterns: usize,
+	//how many times each primitive was placed, by name
+	pub primitive_usage: BTreeMap<String, usize>,
+	pub bridges: usize,
+	//bridges left out because there wasn't time for them between the last note and the pattern, or the last notes hid them
+	pub bridges_skipped: usize,
+	//how many picks each repetition rule changed, and how many picks motif mode repeated on purpose
+	pub cooldown_changes: usize,
+	pub max_share_changes: usize,
+	pub motif_reuses: usize,
+	//peaks in phrases that sound like an earlier phrase, patterns placed again because of them,
+	//and repeats the sabers couldn't flow into that got a pattern picked as usual
+	pub repeated_phrase_peaks: usize,
+	pub phrase_repeats: usize,
+	pub phrase_repeat_misses: usize,
+	//drops found in the song, how many came after a build-up, and what the map did around them
+	pub drops: usize,
+	pub drops_after_build_up: usize,
+	pub drop_thinned_peaks: usize,
+	pub drop_cut_notes: usize,
+	pub drop_signatures: usize,
+	pub drop_wall_bursts: usize,
+	pub drop_lighting_hits: usize,
+	//dynamic cuts turned around to keep forehand and backhand alternating, and resets allowed at section boundaries
+	pub parity_repairs: usize,
+	pub parity_resets: usize,
+	//dynamic patterns generated again because the sabers crossed, clashed or swung through each other
+	pub hand_issue_retries: usize,
+	//notes in dynamic patterns picked again because the swing to them was uncomfortable
+	pub uncomfortable_swings: usize,
+	//dynamic notes the placement solver moved to a legal cell or cut, and notes it couldn't place anywhere
+	pub placement_moves: usize,
+	pub unplaceable_notes: usize,
+	//dynamic notes whose layer or cut came from the pitch, and patterns whose either hand notes got their saber from it
+	pub pitch_layers: usize,
+	pub pitch_cuts: usize,
+	pub pitch_hands: usize,
+	//peaks the density target left empty, quiet peaks it kept, patterns it made shorter or longer,
+	//and the notes per second the map got against its target, overall and by section
+	pub density_dropped_peaks: usize,
+	pub density_kept_peaks: usize,
+	pub density_shorter_patterns: usize,
+	pub density_longer_patterns: usize,
+	pub density_target_nps: f64,
+	pub density_nps: f64,
+	pub density_sections: Vec<SectionDensity>,
+	//notes moved to the other saber or left out to keep each saber within the difficulty's swing-rate limits
+	pub swing_rate_moves: usize,
+	pub swing_rate_drops: usize,
+	//notes turned around after notes were moved or left out, so their saber keeps alternating forehand and backhand
+	pub swing_rate_turns: usize,
+	//how many partial maps were scored and the penalty of the map that was kept, lower is better
+	pub search_maps_scored: usize,
+	pub search_penalty: f64,
+	//generations the finished map was evolved for, and its penalty before and after
+	pub refine_generations: usize,
+	pub refine_start_penalty: f64,
+	pub refine_penalty: f64,
+}
+
+/*
+* Where notes sit and which way they're cut, per hand.
+* Heatmaps are [layer][column] with layer 0 at the bottom, cut directions are indexed by cut direction.
+*/
+#[derive(Serialize, Default)]
+struct NoteCoverage {
+	heatmaps: [[[usize; 4]; 3]; 2],
+	cut_directions: [[usize; 9]; 2],
+	//notes that get their hand when they're placed
+	either_hand_notes: usize,
+	bombs: usize,
+}
+
+#[derive(Serialize)]
+struct PoolReport {
+	name: String,
+	patterns: usize,
+	notes: usize,
+}
+
+#[derive(Serialize)]
+struct PatternUsage {
+	//the pattern id, or the template id with the number of patterns it expanded to
+	id: String,
+	pool: String,
+	variants: usize,
+	notes: usize,
+	times_used: usize,
+	times_repeated: usize,
+	times_on_drops: usize,
+}
+
+#[derive(Serialize)]
+struct VisionBlockReport {
+	//beat of the hidden note
+	beat_time: f64,
+	blocker: (i64, i64),
+	hidden: (i64, i64),
+}
+
+#[derive(Serialize)]
+struct CoverageReport {
+	pools: Vec<PoolReport>,
+	patterns: Vec<PatternUsage>,
+	never_selected: Vec<String>,
+	//notes in the generated map that swing the same way as the last note on their hand
+	parity_breaks: usize,
+	vision_blocks: Vec<VisionBlockReport>,
+	//crossovers, hand clashes and swings through the other saber left in the map, by rule
+	hand_issues: BTreeMap<String, usize>,
+	//angle change, travel and wrist rotation between the notes of each saber
+	swings: [SwingSummary; 2],
+	library: NoteCoverage,
+	generated: NoteCoverage,
+	stats: GenerationStats,
+}
+
+/*
+* cargo run -- report                         generate a map in memory and report on it and the library
+* cargo run -- report --json output/report.json    also write the report as json
+* cargo run -- report --seed 1234                   report on the map made from that seed
+*/
+pub fn run(args: &[String]) {
+	let json_path = args
+		.iter()
+		.position(|arg| arg == "--json")
+		.map(|position| {
+			args.get(position + 1)
+				.cloned()
+				.unwrap_or_else(|| "output/report.json".to_owned())
+		});
+
+	let mut processed_notes = Vec::new();
+	let mut stats = GenerationStats::default();
+	let report = match build_bsaber_map(&mut processed_notes, &mut stats, get_seed(args), false)
+		.and_then(|_| coverage_report(&processed_notes, stats))
+	{
+		Ok(report) => report,
+		Err(error) => {
+			println!("{}", error);
+			return;
+		}
+	};
+	println!("{}", report_text(&report));
+	if let Some(json_path) = json_path {
+		let report_json =
+			serde_json::to_string_pretty(&report).expect("Could not convert report to json");
+		if let Some(json_folder) = Path::new(&json_path).parent() {
+			fs::create_dir_all(json_folder).expect("Could not create report folder");
+		}
+		fs::write(&json_path, report_json).expect("Could not write report json");
+		println!("Report written to {}", json_path);
+	}
+}
+
+fn coverage_report(processed_notes: &[PatternNote], stats: GenerationStats) -> Result<CoverageReport, String> {
+	let settings = get_generator_settings()?;
+	let pattern_collection = load_library(&settings.library)?;
+	let pools: [(&str, &Vec<Pattern>); 3] = [
+		("easy", &pattern_collection.easy_patterns),
+		("normal", &pattern_collection.normal_patterns),
+		("hard", &pattern_collection.hard_patterns),
+	];
+
+	let mut pool_reports = Vec::new();
+	let mut patterns: Vec<PatternUsage> = Vec::new();
+	let mut library = NoteCoverage::default();
+	for (pool_name, pool) in pools.iter() {
+		pool_reports.push(PoolReport {
+			name: pool_name.to_string(),
+			patterns: pool.len(),
+			notes: pool.iter().map(|pattern| pattern.notes.len()).sum(),
+		});
+		let pool_start = patterns.len();
+		for pattern in pool.iter() {
+			add_note_coverage(&mut library, &pattern.notes);
+			//the patterns a template expanded to share one line, under the template id
+			let id = template_base_id(pattern);
+			if let Some(usage) = patterns[pool_start..].iter_mut().find(|usage| usage.id == id) {
+				usage.variants += 1;
+				continue;
+			}
+			let times = |usage: &BTreeMap<String, usize>| usage.get(id).cloned().unwrap_or(0);
+			patterns.push(PatternUsage {
+				id: id.to_owned(),
+				pool: pool_name.to_string(),
+				variants: 1,
+				notes: pattern.notes.len(),
+				times_used: times(&stats.pattern_usage),
+				times_repeated: times(&stats.phrase_repeat_usage),
+				times_on_drops: times(&stats.drop_usage),
+			});
+		}
+	}
+	patterns.sort_by(|a, b| b.times_used.cmp(&a.times_used).then(a.id.cmp(&b.id)));
+	let never_selected = patterns
+		.iter()
+		.filter(|pattern| pattern.times_used + pattern.times_repeated + pattern.times_on_drops == 0)
+		.map(|pattern| pattern.id.to_owned())
+		.collect();
+
+	let parity_breaks = find_parity_breaks(processed_notes, Some(&settings.parity)).len();
+	let vision_blocks = find_vision_blocks(processed_notes, get_vision_window_beats()?)
+		.iter()
+		.map(|(blocker, hidden)| VisionBlockReport {
+			beat_time: hidden.beat_time.unwrap_or(0.0),
+			blocker: (blocker.x, blocker.y),
+			hidden: (hidden.x, hidden.y),
+		})
+		.collect();
+	let mut hand_issues = BTreeMap::new();
+	for hand_issue in find_hand_issues(processed_notes, settings.crossovers_allowed()) {
+		*hand_issues.entry(hand_issue.rule.to_owned()).or_insert(0) += 1;
+	}
+	let mut generated = NoteCoverage::default();
+	add_note_coverage(&mut generated, processed_notes);
+
+	Ok(CoverageReport {
+		pools: pool_reports,
+		patterns,
+		never_selected,
+		parity_breaks,
+		vision_blocks,
+		hand_issues,
+		swings: summarize_swings(processed_notes),
+		library,
+		generated,
+		stats,
+	})
+}
+
+fn add_note_coverage(coverage: &mut NoteCoverage, notes: &[PatternNote]) {
+	for note in notes {
+		match note.note_type {
+			0 | 1 => {
+				let hand = note.note_type as usize;
+				if (0..4).contains(&note.x) && (0..3).contains(&note.y) {
+					coverage.heatmaps[hand][note.y as usize][note.x as usize] += 1;
+				}
+				if (0..9).contains(&note.cut_direction) {
+					coverage.cut_directions[hand][note.cut_direction as usize] += 1;
+				}
+			}
+			3 => coverage.bombs += 1,
+			_ => coverage.either_hand_notes += 1,
+		}
+	}
+}
+
+fn report_text(report: &CoverageReport) -> String {
+	let mut output = String::from("Pattern library\n");
+	for pool in &report.pools {
+		output.push_str(&format!(
+			"  {:<8}{:>4} patterns {:>5} notes\n",
+			pool.name, pool.patterns, pool.notes
+		));
+	}
+
+	output.push_str(&format!(
+		"\nPattern usage ({} dynamic patterns, {} bridges, {} bridges left out for lack of time or hidden)\n",
+		report.stats.dynamic_patterns, report.stats.bridges, report.stats.bridges_skipped
+	));
+	for pattern in report.patterns.iter().filter(|pattern| pattern.times_used > 0) {
+		let variants = if pattern.variants > 1 {
+			format!(" ({} variants)", pattern.variants)
+		} else {
+			String::new()
+		};
+		output.push_str(&format!(
+			"  {:>4}  {:<8}{}{}\n",
+			pattern.times_used, pattern.pool, pattern.id, variants
+		));
+	}
+	for (title, usage) in &[
+		("Placed again on repeated phrases", &report.stats.phrase_repeat_usage),
+		("Drop signatures", &report.stats.drop_usage),
+	] {
+		if !usage.is_empty() {
+			output.push_str(&format!("\n{}\n", title));
+			for (id, times_used) in usage.iter() {
+				output.push_str(&format!("  {:>4}  {}\n", times_used, id));
+			}
+		}
+	}
+
+	if !report.stats.primitive_usage.is_empty() {
+		output.push_str("\nPrimitives\n");
+		for (name, times_used) in &report.stats.primitive_usage {
+			output.push_str(&format!("  {:>4}  {}\n", times_used, name));
+		}
+	}
+
+	let pool_usage = |pool: &str| report.stats.pool_usage.get(pool).cloned().unwrap_or(0);
+	output.push_str(&format!(
+		"\nPools: {} easy, {} normal and {} hard library patterns placed\n",
+		pool_usage("easy"),
+		pool_usage("normal"),
+		pool_usage("hard")
+	));
+	output.push_str(&format!(
+		"Repetition rules: cooldown changed {} picks, max share changed {} picks, motif mode repeated {} picks\n",
+		report.stats.cooldown_changes, report.stats.max_share_changes, report.stats.motif_reuses
+	));
+
+	output.push_str(&format!(
+		"Phrases: {} peaks repeat an earlier phrase, {} patterns placed again, {} picked as usual because the sabers couldn't flow into them\n",
+		report.stats.repeated_phrase_peaks, report.stats.phrase_repeats, report.stats.phrase_repeat_misses
+	));
+	output.push_str(&format!(
+		"Drops: {} found ({} after a build-up), {} peaks left empty and {} notes cut before them, {} signature patterns, {} wall bursts, {} lighting hits\n",
+		report.stats.drops,
+		report.stats.drops_after_build_up,
+		report.stats.drop_thinned_peaks,
+		report.stats.drop_cut_notes,
+		report.stats.drop_signatures,
+		report.stats.drop_wall_bursts,
+		report.stats.drop_lighting_hits
+	));
+
+	output.push_str(&format!(
+		"Parity: {} dynamic cuts repaired, {} resets at section boundaries, {} breaks in the map\n",
+		report.stats.parity_repairs, report.stats.parity_resets, report.parity_breaks
+	));
+
+	output.push_str(&format!(
+		"Placement: {} dynamic notes moved to a legal cell or cut, {} couldn't be placed\n",
+		report.stats.placement_moves, report.stats.unplaceable_notes
+	));
+
+	output.push_str(&format!(
+		"Pitch: {} dynamic layers and {} dynamic cuts set by pitch, {} patterns given a saber by pitch\n",
+		report.stats.pitch_layers, report.stats.pitch_cuts, report.stats.pitch_hands
+	));
+
+	if !report.stats.density_sections.is_empty() {
+		output.push_str(&format!(
+			"Density: {:.2} notes per second against a target of {:.2}, {} peaks left empty, {} quiet peaks kept, {} shorter and {} longer patterns\n",
+			report.stats.density_nps,
+			report.stats.density_target_nps,
+			report.stats.density_dropped_peaks,
+			report.stats.density_kept_peaks,
+			report.stats.density_shorter_patterns,
+			report.stats.density_longer_patterns
+		));
+		for section in &report.stats.density_sections {
+			output.push_str(&format!(
+				"  beat {:>6.1}  {:>5.2} nps, target {:.2}\n",
+				section.start_beat, section.achieved_nps, section.target_nps
+			));
+		}
+	}
+
+	output.push_str(&format!(
+		"Swing rate: {} notes moved to the other saber and {} left out to stay within the limits, {} notes turned around after notes were moved or left out\n",
+		report.stats.swing_rate_moves, report.stats.swing_rate_drops, report.stats.swing_rate_turns
+	));
+	output.push_str(&format!(
+		"Search: {} partial maps scored, penalty {:.1}\n",
+		report.stats.search_maps_scored, report.stats.search_penalty
+	));
+	if report.stats.refine_generations > 0 {
+		output.push_str(&format!(
+			"Refine: {} generations, penalty {:.1} -> {:.1}\n",
+			report.stats.refine_generations, report.stats.refine_start_penalty, report.stats.refine_penalty
+		));
+	}
+
+	output.push_str(&format!(
+		"Hands: {} dynamic patterns generated again",
+		report.stats.hand_issue_retries
+	));
+	for (rule, count) in &report.hand_issues {
+		output.push_str(&format!(", {} {}", count, rule));
+	}
+	output.push('\n');
+
+	output.push_str(&format!(
+		"\nSwings ({} dynamic notes picked again)      angle change   travel   wrist rotation   uncomfortable\n",
+		report.stats.uncomfortable_swings
+	));
+	for (hand_name, summary) in ["left", "right"].iter().zip(report.swings.iter()) {
+		output.push_str(&format!(
+			"  {:<6}{:>5} transitions  avg{:>10.1}{:>9.2}{:>17.1}{:>16}\n",
+			hand_name,
+			summary.transitions,
+			summary.average.angle_change,
+			summary.average.travel,
+			summary.average.wrist_rotation,
+			summary.uncomfortable
+		));
+		output.push_str(&format!(
+			"  {:<23}max{:>10.1}{:>9.2}{:>17.1}\n",
+			"", summary.max.angle_change, summary.max.travel, summary.max.wrist_rotation
+		));
+	}
+
+	output.push_str(&format!("\nVision blocks ({})\n", report.vision_blocks.len()));
+	for vision_block in &report.vision_blocks {
+		output.push_str(&format!(
+			"  beat {:>8.2}  {},{} hidden by {},{}\n",
+			vision_block.beat_time,
+			vision_block.hidden.0,
+			vision_block.hidden.1,
+			vision_block.blocker.0,
+			vision_block.blocker.1
+		));
+	}
+
+	output.push_str(&format!("\nNever selected ({})\n", report.never_selected.len()));
+	for pattern_id in &report.never_selected {
+		output.push_str(&format!("  {}\n", pattern_id));
+	}
+
+	output.push_str("\nCut directions      library  left right   generated  left right\n");
+	for (cut_direction, cut_name) in CUT_NAMES.iter().enumerate() {
+		output.push_str(&format!(
+			"  {:<18}{:>13}{:>6}{:>18}{:>6}\n",
+			cut_name,
+			report.library.cut_directions[0][cut_direction],
+			report.library.cut_directions[1][cut_direction],
+			report.generated.cut_directions[0][cut_direction],
+			report.generated.cut_directions[1][cut_direction]
+		));
+	}
+
+	output.push_str(&coverage_heatmaps("Library", &report.library));
+	output.push_str(&coverage_heatmaps("Generated", &report.generated));
+	output
+}
+
+fn coverage_heatmaps(title: &str, coverage: &NoteCoverage) -> String {
+	let mut output = format!(
+		"\n{} grid            left                    right\n",
+		title
+	);
+	for layer in (0..3).rev() {
+		output.push_str(&format!("  layer {}     ", layer));
+		for (hand, heatmap) in coverage.heatmaps.iter().enumerate() {
+			if hand > 0 {
+				output.push_str("    ");
+			}
+			for count in heatmap[layer].iter() {
+				output.push_str(&format!("{:>6}", count));
+			}
+		}
+		output.push('\n');
+	}
+	output.push_str(&format!(
+		"  {} bombs, {} notes without a fixed hand\n",
+		coverage.bombs, coverage.either_hand_notes
+	));
+	output
+}
@@ -0,0 +1,158 @@
+use crate::bombs::NOTE_TYPE_BOMB;
+use crate::flow::note_order;
+use crate::parity::find_parity_breaks;
+use crate::settings::SearchSettings;
+use crate::swing::summarize_swings;
+use crate::{beat_is_free, place_beat, MapContext, MapState, PatternNote};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+//how far back (in beats) from a new pattern the notes it's scored against go
+const SCORE_WINDOW_BEATS: f64 = 8.0;
+//more notes than this inside one beat is too dense
+const MAX_NOTES_PER_BEAT: usize = 4;
+//how much each problem adds to a map's penalty
+const BRIDGE_PENALTY: f64 = 1.0;
+const UNCOMFORTABLE_SWING_PENALTY: f64 = 2.0;
+const PARITY_BREAK_PENALTY: f64 = 4.0;
+const REPEAT_PENALTY: f64 = 0.5;
+const DENSITY_PENALTY: f64 = 1.0;
+
+/*
+* Place every peak with one pick each, scoring as it goes so the penalty can be compared with beam search
+*/
+pub fn greedy_search<'a>(mut state: MapState<'a>, context: &MapContext<'a>) -> MapState<'a> {
+	for beat_id in 0..context.beats.len() {
+		if place_and_score(&mut state, context, beat_id) {
+			state.stats.search_maps_scored += 1;
+		}
+	}
+	state
+}
+
+/*
+* Keep the beam_width partial maps with the lowest penalty.
+* At every free peak each of them tries branches different picks, each with its own rng seeded from its parent's,
+* so the same seed always makes the same map.
+*/
+pub fn beam_search<'a>(
+	state: MapState<'a>,
+	context: &MapContext<'a>,
+	settings: &SearchSettings,
+) -> MapState<'a> {
+	let beam_width = settings.beam_width.max(1);
+	let branches = settings.branches.max(1);
+	let mut maps_scored = 0;
+	let mut beam = vec![state];
+	for beat_id in 0..context.beats.len() {
+		let mut next_beam = Vec::new();
+		for mut parent in beam {
+			//a map that's still busy with its last pattern carries on as it is
+			if !beat_is_free(&parent, context, beat_id) {
+				next_beam.push(parent);
+				continue;
+			}
+			for _ in 0..branches {
+				let mut child = parent.clone();
+				child.rng = StdRng::seed_from_u64(parent.rng.gen());
+				place_and_score(&mut child, context, beat_id);
+				maps_scored += 1;
+				next_beam.push(child);
+			}
+		}
+		//the sort is stable so ties keep the order they were made in
+		next_beam.sort_by(|a, b| {
+			a.stats
+				.search_penalty
+				.partial_cmp(&b.stats.search_penalty)
+				.unwrap()
+		});
+		//picks that came out the same only take up one place in the beam
+		next_beam.dedup_by(|a, b| a.contents == b.contents);
+		next_beam.truncate(beam_width);
+		beam = next_beam;
+	}
+
+	let mut best = beam.into_iter().next().expect("Beam search kept no maps");
+	best.stats.search_maps_scored = maps_scored;
+	println!(
+		"Beam search scored {} partial maps, best penalty {:.1}",
+		maps_scored, best.stats.search_penalty
+	);
+	best
+}
+
+/*
+* Place a peak and add the penalty for what it placed to the map's total:
+* flow, bridges and uncomfortable swings, parity, swings the same way twice,
+* repetition, library patterns that have been used before, and density, too many notes inside a beat
+*/
+fn place_and_score<'a>(state: &mut MapState<'a>, context: &MapContext<'a>, beat_id: usize) -> bool {
+	let notes_before = state.processed_notes.len();
+	let bridges_before = state.stats.bridges;
+	let repeats_before = state.repetition_tracker.repeated_pairs();
+	if !place_beat(state, context, beat_id) {
+		return false;
+	}
+
+	let mut penalty = (state.stats.bridges - bridges_before) as f64 * BRIDGE_PENALTY
+		+ new_notes_penalty(&state.processed_notes, notes_before, context);
+	//motif mode repeats patterns on purpose
+	if !context.settings.repetition.motif_mode {
+		penalty += (state.repetition_tracker.repeated_pairs() - repeats_before) as f64 * REPEAT_PENALTY;
+	}
+	state.stats.search_penalty += penalty;
+	true
+}
+
+/*
+* Score the new notes against the notes just before them, only counting the problems they add
+*/
+fn new_notes_penalty(processed_notes: &[PatternNote], notes_before: usize, context: &MapContext) -> f64 {
+	let new_notes = &processed_notes[notes_before..];
+	let window_start = match new_notes
+		.iter()
+		.map(note_order)
+		.min_by(|a, b| a.partial_cmp(b).unwrap())
+	{
+		Some(first_time) => first_time - SCORE_WINDOW_BEATS,
+		None => return 0.0,
+	};
+	let window_before: Vec<PatternNote> = processed_notes[..notes_before]
+		.iter()
+		.filter(|note| note_order(note) >= window_start)
+		.cloned()
+		.collect();
+	let mut window_after = window_before.clone();
+	window_after.extend(new_notes.iter().cloned());
+
+	let uncomfortable = |notes: &[PatternNote]| -> usize {
+		summarize_swings(notes).iter().map(|summary| summary.uncomfortable).sum()
+	};
+	let parity_breaks =
+		|notes: &[PatternNote]| find_parity_breaks(notes, Some(&context.settings.parity)).len();
+	let new_uncomfortable = uncomfortable(&window_after).saturating_sub(uncomfortable(&window_before));
+	let new_parity_breaks = parity_breaks(&window_after).saturating_sub(parity_breaks(&window_before));
+
+	//every note past the limit in the beat leading up to a new note
+	let too_dense = new_notes
+		.iter()
+		.filter(|note| note.note_type != NOTE_TYPE_BOMB)
+		.filter(|note| {
+			let time = note_order(note);
+			let notes_in_beat = window_after
+				.iter()
+				.filter(|other| other.note_type != NOTE_TYPE_BOMB)
+				.filter(|other| {
+					let other_time = note_order(other);
+					other_time > time - 1.0 && other_time <= time
+				})
+				.count();
+			notes_in_beat > MAX_NOTES_PER_BEAT
+		})
+		.count();
+
+	new_uncomfortable as f64 * UNCOMFORTABLE_SWING_PENALTY
+		+ new_parity_breaks as f64 * PARITY_BREAK_PENALTY
+		+ too_dense as f64 * DENSITY_PENALTY
+}
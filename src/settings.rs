@@ -0,0 +1,469 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+pub const SETTINGS_PATH: &str = "src/song/generator.json";
+
+/*
+* Options for how maps are generated, read from src/song/generator.json.
+* Anything left out of the file keeps its default.
+*/
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct GeneratorSettings {
+	//the difficulty the map is generated for
+	pub difficulty: Difficulty,
+	pub library: LibrarySettings,
+	pub repetition: RepetitionSettings,
+	pub parity: ParitySettings,
+	pub hands: HandSettings,
+	pub transitions: TransitionSettings,
+	pub search: SearchSettings,
+	pub refine: RefineSettings,
+	pub primitives: PrimitiveSettings,
+	pub pitch: PitchSettings,
+	pub phrases: PhraseSettings,
+	pub drops: DropSettings,
+	pub intensity: IntensitySettings,
+	pub density: DensitySettings,
+	pub swing_limits: SwingLimitSettings,
+}
+
+impl GeneratorSettings {
+	pub fn crossovers_allowed(&self) -> bool {
+		self.hands.allow_crossovers && self.difficulty >= self.hands.crossover_min_difficulty
+	}
+
+	//what the map does around a drop at the difficulty it's generated for, nothing if it isn't listed
+	pub fn drop_response(&self) -> Option<&DropResponse> {
+		self.drops.responses.get(&self.difficulty)
+	}
+
+	//how fast each saber can swing at the difficulty it's generated for, no limit if it isn't listed
+	pub fn swing_limit(&self) -> Option<&SwingLimit> {
+		self.swing_limits.limits.get(&self.difficulty)
+	}
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Difficulty {
+	Easy,
+	Normal,
+	Hard,
+	Expert,
+	//we generate ExpertPlus.dat
+	#[default]
+	ExpertPlus,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct LibrarySettings {
+	//pattern library folders, a pattern replaces one with the same id from an earlier root
+	pub roots: Vec<String>,
+	//project manifest applied on top of the manifest of every root, if the file exists
+	pub manifest: Option<String>,
+	//start from the library that was built into the generator
+	pub use_embedded: bool,
+}
+
+impl Default for LibrarySettings {
+	fn default() -> LibrarySettings {
+		LibrarySettings {
+			roots: vec![
+				"~/.bsaber_generator/patterns".to_owned(),
+				"src/patterns".to_owned(),
+			],
+			manifest: Some("src/song/library.json".to_owned()),
+			use_embedded: true,
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct RepetitionSettings {
+	//a library pattern can't be picked again until this many other library patterns have been placed
+	pub cooldown_patterns: usize,
+	//the largest fraction of all the placed patterns, dynamic ones included, any one library pattern can make up
+	pub max_share: f64,
+	//reuse the same pattern every time the same pitch bin comes back
+	pub motif_mode: bool,
+}
+
+impl Default for RepetitionSettings {
+	fn default() -> RepetitionSettings {
+		RepetitionSettings {
+			cooldown_patterns: 3,
+			max_share: 0.2,
+			motif_mode: false,
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct ParitySettings {
+	//let a hand start again on either swing after a long gap, instead of always alternating forehand and backhand
+	pub allow_resets: bool,
+	//how long a gap between notes (in beats) counts as a section boundary
+	pub reset_gap_beats: f64,
+}
+
+impl Default for ParitySettings {
+	fn default() -> ParitySettings {
+		ParitySettings {
+			allow_resets: false,
+			reset_gap_beats: 4.0,
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct HandSettings {
+	//let a saber's notes cross to the other side of the other saber on purpose
+	pub allow_crossovers: bool,
+	//crossovers are only allowed when generating for this difficulty or harder
+	pub crossover_min_difficulty: Difficulty,
+}
+
+impl Default for HandSettings {
+	fn default() -> HandSettings {
+		HandSettings {
+			allow_crossovers: false,
+			crossover_min_difficulty: Difficulty::Expert,
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct TransitionSettings {
+	//model file written by the train command, dynamic patterns use the hard-coded transitions without one
+	pub model: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SearchMode {
+	//one pick per peak, never reconsidered
+	#[default]
+	Greedy,
+	//keep the best few partial maps at every peak and finish with the best one
+	Beam,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct SearchSettings {
+	pub mode: SearchMode,
+	//how many partial maps beam search keeps after each peak
+	pub beam_width: usize,
+	//how many different picks each partial map tries at each peak
+	pub branches: usize,
+}
+
+impl Default for SearchSettings {
+	fn default() -> SearchSettings {
+		SearchSettings {
+			mode: SearchMode::Greedy,
+			beam_width: 8,
+			branches: 4,
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct RefineSettings {
+	//how many generations the finished map is evolved for, 0 keeps the map as it was generated
+	pub generations: usize,
+	//how many candidate maps are kept from one generation to the next
+	pub population: usize,
+	//the chance of each note in a new candidate being moved or turned
+	pub mutation_rate: f64,
+	//the chance of a new candidate having one of its patterns swapped for another from the library
+	pub pattern_swap_rate: f64,
+	//the chance of a new candidate taking the start of one parent and the rest of another
+	pub crossover_rate: f64,
+	//notes per second the map should average, for a difficulty without a density target
+	pub target_nps: f64,
+	//where the best and average penalty of every generation is written
+	pub history: String,
+}
+
+impl Default for RefineSettings {
+	fn default() -> RefineSettings {
+		RefineSettings {
+			generations: 0,
+			population: 16,
+			mutation_rate: 0.02,
+			pattern_swap_rate: 0.5,
+			crossover_rate: 0.7,
+			target_nps: 4.0,
+			history: "output/fitness_history.json".to_owned(),
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct PrimitiveSettings {
+	//the percentage of peaks that get a stream, jump, stack, tower, window or slider, taken from the peaks that would get a dynamic pattern first
+	pub chance: i64,
+}
+
+impl Default for PrimitiveSettings {
+	fn default() -> PrimitiveSettings {
+		PrimitiveSettings { chance: 20 }
+	}
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct PitchSettings {
+	//put dynamic notes on the layer their pitch suggests, and cut up when the melody rises and down when it falls
+	pub layers_and_cuts: bool,
+	//where layers 1 and 2 start, as a fraction of the way from the song's lowest pitch to its highest
+	pub layer_starts: [f64; 2],
+	//pitch changes smaller than this fraction of the song's range are flat and leave the cut alone
+	pub contour_tolerance: f64,
+	//give notes that can go on either hand to the left saber below hand_split and to the right saber above it
+	pub hand_by_register: bool,
+	pub hand_split: f64,
+}
+
+impl Default for PitchSettings {
+	fn default() -> PitchSettings {
+		PitchSettings {
+			layers_and_cuts: false,
+			layer_starts: [0.4, 0.75],
+			contour_tolerance: 0.05,
+			hand_by_register: false,
+			hand_split: 0.5,
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct PhraseSettings {
+	//give a phrase that sounds like an earlier one the same patterns again, needs chroma in peak_times.txt
+	pub enabled: bool,
+	//how many peaks make a phrase
+	pub phrase_peaks: usize,
+	//how alike (0 to 1) the chroma of two phrases has to be for one to count as a repeat of the other
+	pub similarity_threshold: f64,
+	//repeat the patterns mirrored left to right instead of the same way round
+	pub mirror_repeats: bool,
+}
+
+impl Default for PhraseSettings {
+	fn default() -> PhraseSettings {
+		PhraseSettings {
+			enabled: true,
+			phrase_peaks: 8,
+			similarity_threshold: 0.9,
+			mirror_repeats: false,
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct DropSettings {
+	//look for build-ups and drops, needs onset strength and energy in peak_times.txt
+	pub enabled: bool,
+	//how far (in seconds) before a peak is looked at to tell if the energy built up to it or fell away
+	pub window_seconds: f64,
+	//how many times louder than the window before it a drop has to be
+	pub drop_jump: f64,
+	//how many times the energy and onset density of the first half of the window the second half needs for a build-up
+	pub build_up_rise: f64,
+	//a window quieter than this fraction of the song's average energy is a break
+	pub break_level: f64,
+	//a loud peak this soon (in seconds) after a drop is part of the same drop
+	pub min_spacing_seconds: f64,
+	//what the map does around a drop, by difficulty
+	pub responses: BTreeMap<Difficulty, DropResponse>,
+}
+
+impl Default for DropSettings {
+	fn default() -> DropSettings {
+		let response = |thin_out_beats, signature, wall_burst_beats| DropResponse {
+			thin_out_beats,
+			signature,
+			signature_pattern: None,
+			wall_burst_beats,
+			lighting_hit: true,
+		};
+		let mut responses = BTreeMap::new();
+		responses.insert(Difficulty::Easy, response(4.0, false, 0.0));
+		responses.insert(Difficulty::Normal, response(2.0, true, 0.0));
+		responses.insert(Difficulty::Hard, response(2.0, true, 1.0));
+		responses.insert(Difficulty::Expert, response(1.0, true, 2.0));
+		responses.insert(Difficulty::ExpertPlus, response(1.0, true, 2.0));
+		DropSettings {
+			enabled: true,
+			window_seconds: 4.0,
+			drop_jump: 1.5,
+			build_up_rise: 1.2,
+			break_level: 0.5,
+			min_spacing_seconds: 16.0,
+			responses,
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct DropResponse {
+	//peaks this many beats before a drop are left empty, and patterns that would run into them are cut short
+	pub thin_out_beats: f64,
+	//place a signature pattern on the drop, the library pattern with signature_pattern's id or a jump without one
+	pub signature: bool,
+	pub signature_pattern: Option<String>,
+	//walls down the outside lanes for this many beats from the drop, 0 for none
+	pub wall_burst_beats: f64,
+	//flash every light on the drop
+	pub lighting_hit: bool,
+}
+
+impl Default for DropResponse {
+	fn default() -> DropResponse {
+		DropResponse {
+			thin_out_beats: 2.0,
+			signature: true,
+			signature_pattern: None,
+			wall_burst_beats: 0.0,
+			lighting_hit: true,
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PoolMode {
+	//easy, normal or hard patterns by how intense the music is at the peak
+	#[default]
+	Intensity,
+	//the old thresholds on the peak's pitch
+	Pitch,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct IntensitySettings {
+	pub pool_mode: PoolMode,
+	//how much onset strength, onset density and energy each count towards a peak's intensity
+	pub onset_weight: f64,
+	pub density_weight: f64,
+	pub energy_weight: f64,
+	//the window (in seconds) around a peak its onset density is counted over
+	pub density_window_seconds: f64,
+	//the intensity (0 to 1) where the normal and the hard pool start, the hard pool also gets the bigger primitives
+	pub pool_starts: [f64; 2],
+}
+
+impl Default for IntensitySettings {
+	fn default() -> IntensitySettings {
+		IntensitySettings {
+			pool_mode: PoolMode::Intensity,
+			onset_weight: 0.4,
+			density_weight: 0.3,
+			energy_weight: 0.3,
+			density_window_seconds: 2.0,
+			pool_starts: [0.35, 0.7],
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct DensitySettings {
+	//notes per second each difficulty should average, a difficulty that isn't listed gets whatever the peaks give it
+	pub target_nps: BTreeMap<Difficulty, f64>,
+	//how long (in beats) a section is, each one gets its own target
+	pub section_beats: f64,
+	//the target of the calmest and of the most intense section, as a multiple of the difficulty's target
+	pub quiet_scale: f64,
+	pub intense_scale: f64,
+	//how far over or under the target (as a fraction of it) peaks are dropped, or quiet peaks kept
+	pub tolerance: f64,
+}
+
+impl Default for DensitySettings {
+	fn default() -> DensitySettings {
+		let mut target_nps = BTreeMap::new();
+		target_nps.insert(Difficulty::Easy, 1.5);
+		target_nps.insert(Difficulty::Normal, 2.5);
+		target_nps.insert(Difficulty::Hard, 3.5);
+		target_nps.insert(Difficulty::Expert, 4.5);
+		target_nps.insert(Difficulty::ExpertPlus, 6.0);
+		DensitySettings {
+			target_nps,
+			section_beats: 16.0,
+			quiet_scale: 0.6,
+			intense_scale: 1.4,
+			tolerance: 0.2,
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct SwingLimitSettings {
+	//how long (in seconds) the window a saber's sustained swing rate is counted over is
+	pub window_seconds: f64,
+	//how fast each saber can swing, by difficulty
+	pub limits: BTreeMap<Difficulty, SwingLimit>,
+}
+
+impl Default for SwingLimitSettings {
+	fn default() -> SwingLimitSettings {
+		let limit = |min_gap_seconds, max_swings_per_second| SwingLimit {
+			min_gap_seconds,
+			max_swings_per_second,
+		};
+		let mut limits = BTreeMap::new();
+		limits.insert(Difficulty::Easy, limit(0.5, 2.0));
+		limits.insert(Difficulty::Normal, limit(0.33, 3.0));
+		limits.insert(Difficulty::Hard, limit(0.25, 4.0));
+		limits.insert(Difficulty::Expert, limit(0.18, 5.0));
+		limits.insert(Difficulty::ExpertPlus, limit(0.12, 6.0));
+		SwingLimitSettings {
+			window_seconds: 2.0,
+			limits,
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct SwingLimit {
+	//the shortest time (in seconds) between two swings of the same saber
+	pub min_gap_seconds: f64,
+	//the most swings one saber can make per second over the window
+	pub max_swings_per_second: f64,
+}
+
+impl Default for SwingLimit {
+	fn default() -> SwingLimit {
+		SwingLimit {
+			min_gap_seconds: 0.12,
+			max_swings_per_second: 6.0,
+		}
+	}
+}
+
+/*
+* The settings from src/song/generator.json, the defaults without it, or why the file can't be used
+*/
+pub fn get_generator_settings() -> Result<GeneratorSettings, String> {
+	if !Path::new(SETTINGS_PATH).exists() {
+		return Ok(GeneratorSettings::default());
+	}
+	let settings_data = fs::read_to_string(SETTINGS_PATH)
+		.map_err(|error| format!("Could not read generator settings: {}", error))?;
+	serde_json::from_str(&settings_data[..]).map_err(|error| format!("Could not parse generator settings: {}", error))
+}
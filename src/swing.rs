@@ -0,0 +1,160 @@
+use crate::flow::{note_order, SAME_TIME_TOLERANCE};
+use crate::PatternNote;
+use serde::Serialize;
+
+//the furthest a cut can turn away from straight back the way the last one came, in degrees
+const MAX_ANGLE_CHANGE: f64 = 90.0;
+//the furthest a saber can travel (in grid cells) per beat between two notes
+const MAX_TRAVEL_PER_BEAT: f64 = 4.0;
+//notes closer together than this (in beats) are fast, and fast notes can't twist the wrist far
+const FAST_GAP: f64 = 0.5;
+const MAX_FAST_WRIST_ROTATION: f64 = 45.0;
+//a dot this close (in beats) after a note on the same saber is a slider, cut in the same swing
+const SLIDER_GAP: f64 = 0.125;
+const CUT_DOT: i64 = 8;
+
+/*
+* Which way each cut direction points in degrees, 0 is right and 90 is up. Dots can be cut at any angle.
+*/
+pub fn cut_angle(cut_direction: i64) -> Option<f64> {
+	match cut_direction {
+		0 => Some(90.0),
+		1 => Some(270.0),
+		2 => Some(180.0),
+		3 => Some(0.0),
+		4 => Some(135.0),
+		5 => Some(45.0),
+		6 => Some(225.0),
+		7 => Some(315.0),
+		_ => None,
+	}
+}
+
+/*
+* How far the line of a cut leans from vertical, from -90 to 90 degrees.
+* A cut and its opposite lean the same way, and the wrist can't roll past horizontal to get from one side to the other.
+*/
+fn cut_tilt(angle: f64) -> f64 {
+	let tilt = (angle - 90.0).rem_euclid(180.0);
+	if tilt > 90.0 {
+		tilt - 180.0
+	} else {
+		tilt
+	}
+}
+
+//the smaller of the two ways round between two angles, 0 to 180 degrees
+fn angle_between(a: f64, b: f64) -> f64 {
+	let difference = (a - b).rem_euclid(360.0);
+	difference.min(360.0 - difference)
+}
+
+/*
+* How a saber moves from one note to the next:
+* angle_change is how far the cut turns away from swinging straight back the way the last one came,
+* travel is how far the saber moves across the grid in cells,
+* wrist_rotation is how far the wrist rolls to tilt the line of the cut, whichever way it's swung.
+*/
+#[derive(Clone, Copy, Default, Serialize)]
+pub struct SwingTransition {
+	pub angle_change: f64,
+	pub travel: f64,
+	pub wrist_rotation: f64,
+}
+
+pub fn swing_transition(prev_note: &PatternNote, note: &PatternNote) -> SwingTransition {
+	let travel = (((note.x - prev_note.x).pow(2) + (note.y - prev_note.y).pow(2)) as f64).sqrt();
+	//a dot is cut at whatever angle flows best from the swing before it
+	let angles = (cut_angle(prev_note.cut_direction), cut_angle(note.cut_direction));
+	let (angle_change, wrist_rotation) = match angles {
+		(Some(prev_angle), Some(angle)) => (
+			angle_between(angle, prev_angle + 180.0),
+			(cut_tilt(angle) - cut_tilt(prev_angle)).abs(),
+		),
+		_ => (0.0, 0.0),
+	};
+	SwingTransition {
+		angle_change,
+		travel,
+		wrist_rotation,
+	}
+}
+
+/*
+* A transition is uncomfortable if the cut turns too far, the saber has to move too fast,
+* or the wrist has to twist too far between fast notes
+*/
+pub fn is_uncomfortable(transition: &SwingTransition, gap_in_beats: f64) -> bool {
+	let travel_per_beat = if gap_in_beats > SAME_TIME_TOLERANCE {
+		transition.travel / gap_in_beats
+	} else {
+		0.0
+	};
+	transition.angle_change > MAX_ANGLE_CHANGE
+		|| travel_per_beat > MAX_TRAVEL_PER_BEAT
+		|| (gap_in_beats < FAST_GAP && transition.wrist_rotation > MAX_FAST_WRIST_ROTATION)
+}
+
+/*
+* Averages and worst cases of every transition one saber makes in a map
+*/
+#[derive(Default, Serialize)]
+pub struct SwingSummary {
+	pub transitions: usize,
+	pub uncomfortable: usize,
+	pub average: SwingTransition,
+	pub max: SwingTransition,
+}
+
+/*
+* Whether a note on the same saber as the note before it needs a swing of its own.
+* Notes cut together and the dots of a slider are part of the swing before them.
+*/
+pub fn starts_swing(prev_note: &PatternNote, note: &PatternNote) -> bool {
+	let gap = note_order(note) - note_order(prev_note);
+	let is_slide = note.cut_direction == CUT_DOT && gap <= SLIDER_GAP + SAME_TIME_TOLERANCE;
+	gap > SAME_TIME_TOLERANCE && !is_slide
+}
+
+/*
+* Follow each saber through the notes in order, index 0 is the left saber and 1 the right.
+* Notes should carry their beat_time, notes cut together by one saber and the dots of a slider are one swing.
+*/
+pub fn summarize_swings(notes: &[PatternNote]) -> [SwingSummary; 2] {
+	let mut summaries = [SwingSummary::default(), SwingSummary::default()];
+	for (hand, summary) in summaries.iter_mut().enumerate() {
+		let mut hand_notes: Vec<&PatternNote> = notes
+			.iter()
+			.filter(|note| note.note_type == hand as i64)
+			.collect();
+		hand_notes.sort_by(|a, b| note_order(a).partial_cmp(&note_order(b)).unwrap());
+
+		let mut total = SwingTransition::default();
+		for pair in hand_notes.windows(2) {
+			let gap = note_order(pair[1]) - note_order(pair[0]);
+			if !starts_swing(pair[0], pair[1]) {
+				continue;
+			}
+			let transition = swing_transition(pair[0], pair[1]);
+			summary.transitions += 1;
+			if is_uncomfortable(&transition, gap) {
+				summary.uncomfortable += 1;
+			}
+			total.angle_change += transition.angle_change;
+			total.travel += transition.travel;
+			total.wrist_rotation += transition.wrist_rotation;
+			summary.max.angle_change = summary.max.angle_change.max(transition.angle_change);
+			summary.max.travel = summary.max.travel.max(transition.travel);
+			summary.max.wrist_rotation = summary.max.wrist_rotation.max(transition.wrist_rotation);
+		}
+		if summary.transitions > 0 {
+			let transitions = summary.transitions as f64;
+			summary.average = SwingTransition {
+				angle_change: total.angle_change / transitions,
+				travel: total.travel / transitions,
+				wrist_rotation: total.wrist_rotation / transitions,
+			};
+		}
+	}
+	summaries
+}
@@ -0,0 +1,278 @@
+use crate::bombs::NOTE_TYPE_BOMB;
+use crate::flow::{next_swing, note_order, opposite_cut, swing_for_cut, Swing, GRID_COLUMNS, SAME_TIME_TOLERANCE};
+use crate::parity::breaks_parity;
+use crate::settings::SwingLimit;
+use crate::swing::starts_swing;
+use crate::template::mirror_cut;
+use crate::PatternNote;
+
+/*
+* What to do with a note so its saber stays within the swing-rate limits
+*/
+pub enum RateFix {
+	Keep,
+	MoveTo(PatternNote),
+	Drop,
+}
+
+//a hand's notes up to a note's time, in order
+fn hand_notes_before<'a>(notes: &[&'a PatternNote], hand: i64, time: f64) -> Vec<&'a PatternNote> {
+	let mut hand_notes: Vec<&PatternNote> = notes
+		.iter()
+		.filter(|other| other.note_type == hand && note_order(other) <= time)
+		.cloned()
+		.collect();
+	hand_notes.sort_by(|a, b| note_order(a).partial_cmp(&note_order(b)).unwrap());
+	hand_notes
+}
+
+/*
+* Whether a note would make its saber swing too soon after its last swing or before its next one,
+* or make more than max_swings_per_second over the window ending on it.
+* Notes after it can come from an earlier pattern that runs on past the start of the note's pattern.
+*/
+pub fn breaks_swing_rate(
+	notes: &[&PatternNote],
+	note: &PatternNote,
+	limit: &SwingLimit,
+	window_seconds: f64,
+	beats_per_minute: f64,
+) -> bool {
+	let time = note_order(note);
+	let next_note = notes
+		.iter()
+		.filter(|other| other.note_type == note.note_type && note_order(other) > time)
+		.min_by(|a, b| note_order(a).partial_cmp(&note_order(b)).unwrap());
+	if let Some(next_note) = next_note {
+		let gap_sec = ((note_order(next_note) - time) / beats_per_minute) * 60.0;
+		if starts_swing(note, next_note) && gap_sec < limit.min_gap_seconds {
+			return true;
+		}
+	}
+	let hand_notes = hand_notes_before(notes, note.note_type, time);
+	let last_note = match hand_notes.last() {
+		Some(last_note) => last_note,
+		None => return false,
+	};
+	if !starts_swing(last_note, note) {
+		return false;
+	}
+	let gap_sec = ((time - note_order(last_note)) / beats_per_minute) * 60.0;
+	if gap_sec < limit.min_gap_seconds {
+		return true;
+	}
+
+	let window_start = time - (window_seconds / 60.0) * beats_per_minute;
+	//the note itself, the hand's first note and every note that starts a swing after it
+	let mut swings = 1;
+	if note_order(hand_notes[0]) > window_start {
+		swings += 1;
+	}
+	swings += hand_notes
+		.windows(2)
+		.filter(|pair| note_order(pair[1]) > window_start && starts_swing(pair[0], pair[1]))
+		.count();
+	swings as f64 > limit.max_swings_per_second * window_seconds
+}
+
+//the way a hand last swung, after all of its notes so far
+fn last_swing(notes: &[&PatternNote], hand: i64, time: f64) -> Option<Swing> {
+	hand_notes_before(notes, hand, time)
+		.iter()
+		.fold(None, |swing, note| next_swing(swing, note.note_type, note.cut_direction))
+}
+
+//whether another note is on the note's cell at the same time
+pub fn is_occupied(notes: &[&PatternNote], note: &PatternNote) -> bool {
+	let time = note_order(note);
+	notes.iter().any(|other| {
+		(note_order(other) - time).abs() <= SAME_TIME_TOLERANCE && other.x == note.x && other.y == note.y
+	})
+}
+
+/*
+* Keep a note that's within its saber's limits. Otherwise move it to the other saber, mirrored across the grid,
+* if that saber is within its limits, can make the cut without breaking parity and that cell is free, or drop it.
+*/
+pub fn fix_swing_rate(
+	notes: &[&PatternNote],
+	note: &PatternNote,
+	limit: &SwingLimit,
+	window_seconds: f64,
+	beats_per_minute: f64,
+) -> RateFix {
+	if note.note_type == NOTE_TYPE_BOMB || !breaks_swing_rate(notes, note, limit, window_seconds, beats_per_minute) {
+		return RateFix::Keep;
+	}
+	let other_hand = 1 - note.note_type;
+	let moved = PatternNote {
+		x: GRID_COLUMNS - 1 - note.x,
+		cut_direction: mirror_cut(note.cut_direction),
+		note_type: other_hand,
+		..note.clone()
+	};
+	let time = note_order(&moved);
+	if is_occupied(notes, &moved)
+		|| breaks_parity(last_swing(notes, other_hand, time), other_hand, moved.cut_direction)
+		|| breaks_swing_rate(notes, &moved, limit, window_seconds, beats_per_minute)
+	{
+		return RateFix::Drop;
+	}
+	RateFix::MoveTo(moved)
+}
+
+/*
+* Follow a saber from its notes already in the map through its new notes and turn around every new note
+* that would swing the same way twice, now that notes were moved to it or left out.
+* A note cut together with the one before it goes the same way as that note. New notes should be in order.
+* Returns how many notes were turned.
+*/
+pub fn turn_for_parity<'n>(
+	map_notes: &[PatternNote],
+	hand: i64,
+	new_notes: impl Iterator<Item = &'n mut PatternNote>,
+) -> usize {
+	let mut hand_notes: Vec<&PatternNote> = map_notes.iter().filter(|note| note.note_type == hand).collect();
+	hand_notes.sort_by(|a, b| note_order(a).partial_cmp(&note_order(b)).unwrap());
+	let mut swing = None;
+	//the time and swing of the note that started the saber's last swing
+	let mut last_note: Option<(f64, Option<Swing>)> = None;
+	for note in hand_notes {
+		let time = note_order(note);
+		if last_note.is_some_and(|(last_time, _)| time - last_time <= SAME_TIME_TOLERANCE) {
+			continue;
+		}
+		swing = next_swing(swing, hand, note.cut_direction);
+		last_note = Some((time, swing_for_cut(hand, note.cut_direction)));
+	}
+
+	let mut turns = 0;
+	for note in new_notes.filter(|note| note.note_type == hand) {
+		let time = note_order(note);
+		let note_swing = swing_for_cut(hand, note.cut_direction);
+		let turn = match last_note {
+			Some((last_time, last_swing)) if time - last_time <= SAME_TIME_TOLERANCE => {
+				last_swing.is_some() && note_swing.is_some() && last_swing != note_swing
+			}
+			_ => breaks_parity(swing, hand, note.cut_direction),
+		};
+		if turn {
+			note.cut_direction = opposite_cut(note.cut_direction);
+			turns += 1;
+		}
+		if !last_note.is_some_and(|(last_time, _)| time - last_time <= SAME_TIME_TOLERANCE) {
+			swing = next_swing(swing, hand, note.cut_direction);
+			last_note = Some((time, swing_for_cut(hand, note.cut_direction)));
+		}
+	}
+	turns
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::parity::find_parity_breaks;
+	use crate::test_notes;
+
+	//at 60 bpm a beat is a second
+	const BEATS_PER_MINUTE: f64 = 60.0;
+	const WINDOW_SECONDS: f64 = 1.0;
+
+	fn limit() -> SwingLimit {
+		SwingLimit {
+			min_gap_seconds: 0.12,
+			max_swings_per_second: 6.0,
+		}
+	}
+
+	//left saber notes cutting down and up in turn
+	fn note(beat_time: f64, cut_direction: i64) -> PatternNote {
+		test_notes::note(1, 0, cut_direction, 0, beat_time)
+	}
+
+	fn breaks(notes: &[PatternNote], note: &PatternNote) -> bool {
+		let notes: Vec<&PatternNote> = notes.iter().collect();
+		breaks_swing_rate(&notes, note, &limit(), WINDOW_SECONDS, BEATS_PER_MINUTE)
+	}
+
+	#[test]
+	fn first_note_is_fine() {
+		assert!(!breaks(&[], &note(0.0, 1)));
+	}
+
+	#[test]
+	fn gap_at_the_limit_is_fine() {
+		assert!(!breaks(&[note(0.0, 1)], &note(0.125, 0)));
+	}
+
+	#[test]
+	fn gap_under_the_limit_breaks() {
+		assert!(breaks(&[note(0.0, 1)], &note(0.1, 0)));
+	}
+
+	#[test]
+	fn gap_to_the_next_note_breaks() {
+		assert!(breaks(&[note(1.0, 1)], &note(0.9, 0)));
+		assert!(!breaks(&[note(1.0, 1)], &note(0.5, 0)));
+	}
+
+	#[test]
+	fn other_saber_doesnt_count() {
+		let other = PatternNote {
+			note_type: 1,
+			..note(0.0, 1)
+		};
+		assert!(!breaks(&[other], &note(0.05, 0)));
+	}
+
+	#[test]
+	fn notes_cut_together_are_one_swing() {
+		assert!(!breaks(&[note(0.0, 1)], &note(0.0, 1)));
+	}
+
+	//what add_pattern does with a pattern's notes: keep, move or drop each note, then turn the sabers that changed
+	fn limit_notes(notes: &[PatternNote]) -> (Vec<PatternNote>, usize) {
+		let mut limited: Vec<PatternNote> = Vec::new();
+		for note in notes {
+			let around: Vec<&PatternNote> = limited.iter().collect();
+			match fix_swing_rate(&around, note, &limit(), WINDOW_SECONDS, BEATS_PER_MINUTE) {
+				RateFix::Keep => limited.push(note.clone()),
+				RateFix::MoveTo(moved) => limited.push(moved),
+				RateFix::Drop => {}
+			}
+		}
+		let turns = (0..2)
+			.map(|hand| turn_for_parity(&[], hand, limited.iter_mut()))
+			.sum();
+		(limited, turns)
+	}
+
+	#[test]
+	fn limited_stream_keeps_parity() {
+		//a left saber stream too fast to swing, the right saber free to take some of it
+		let cuts = [1, 0];
+		let notes: Vec<PatternNote> = (0..12).map(|note_id| note(note_id as f64 * 0.1, cuts[note_id % 2])).collect();
+		let (limited, turns) = limit_notes(&notes);
+		assert!(limited.len() < notes.len());
+		assert!(turns > 0);
+		assert!(find_parity_breaks(&limited, None).is_empty());
+	}
+
+	#[test]
+	fn turns_follow_the_map() {
+		//the saber last swung down in the map, so both new notes turn
+		let map_notes = [note(0.0, 1)];
+		let mut new_notes = [note(1.0, 1), note(2.0, 0)];
+		assert_eq!(turn_for_parity(&map_notes, 0, new_notes.iter_mut()), 2);
+		assert_eq!((new_notes[0].cut_direction, new_notes[1].cut_direction), (0, 1));
+	}
+
+	#[test]
+	fn swings_per_second_edge() {
+		let cuts = [1, 0];
+		let notes: Vec<PatternNote> = (0..7).map(|note_id| note(note_id as f64 * 0.15, cuts[note_id % 2])).collect();
+		//six swings in the second up to the sixth note, seven up to the seventh
+		assert!(!breaks(&notes[..5], &notes[5]));
+		assert!(breaks(&notes[..6], &notes[6]));
+	}
+}
@@ -0,0 +1,319 @@
+use crate::bombs::NOTE_TYPE_BOMB;
+use crate::flow::opposite_cut;
+use crate::{Pattern, PatternNote, PatternSource, PatternWall};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+//cut directions going clockwise from up, used to rotate a cut in 45 degree steps
+const CLOCKWISE_CUTS: [i64; 8] = [0, 5, 3, 7, 1, 6, 2, 4];
+//the cut seen in a left/right mirror, indexed by cut direction
+const MIRRORED_CUTS: [i64; 9] = [0, 1, 3, 2, 5, 4, 7, 6, 8];
+
+/*
+* A pattern whose numbers can be written as expressions over declared variables, e.g.
+* "variables": { "base": [0, 1, 2], "dir": [0, 1] } with "x": "$base+1" and "cut_direction": "opposite($dir)".
+* Supported expressions are $name, integers, + and - between them, and the cut helpers
+* opposite(expr), mirror(expr) and rotate(expr, steps) where steps are clockwise 45 degree turns.
+*/
+#[derive(Deserialize)]
+pub struct PatternTemplate {
+	description: String,
+	variables: BTreeMap<String, Vec<i64>>,
+	notes: Vec<TemplateNote>,
+	#[serde(default)]
+	obstacles: Vec<TemplateWall>,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(untagged)]
+enum TemplateValue {
+	Value(i64),
+	Expression(String),
+}
+
+#[derive(Deserialize)]
+struct TemplateNote {
+	x: TemplateValue,
+	y: TemplateValue,
+	cut_direction: TemplateValue,
+	note_type: TemplateValue,
+	description: String,
+	#[serde(default)]
+	beat_time_rel: usize,
+	#[serde(default)]
+	beat_time: Option<f64>,
+}
+
+#[derive(Deserialize)]
+struct TemplateWall {
+	x: TemplateValue,
+	y: TemplateValue,
+	duration: f64,
+	padding: f64,
+	width: f64,
+	wall_type: i64,
+	description: String,
+	#[serde(default)]
+	wall_time_rel: usize,
+	#[serde(default)]
+	wall_time: Option<f64>,
+}
+
+pub fn is_template(pattern_json: &serde_json::Value) -> bool {
+	pattern_json.get("variables").is_some()
+}
+
+/*
+* Every concrete pattern the template can make, or the first expression that can't be worked out.
+* Bindings that put a note or wall off the grid, or give an unknown cut direction or note type, are left out.
+*/
+pub fn expand_template(template: &PatternTemplate, template_id: &str) -> Result<Vec<Pattern>, String> {
+	let mut patterns = Vec::new();
+	for bindings in all_bindings(&template.variables) {
+		if let Some(pattern) = bind_template(template, template_id, &bindings)? {
+			patterns.push(pattern);
+		}
+	}
+	Ok(patterns)
+}
+
+fn all_bindings(variables: &BTreeMap<String, Vec<i64>>) -> Vec<BTreeMap<String, i64>> {
+	let mut bindings = vec![BTreeMap::new()];
+	for (name, values) in variables {
+		let mut next_bindings = Vec::new();
+		for binding in &bindings {
+			for value in values {
+				let mut next_binding = binding.clone();
+				next_binding.insert(name.to_owned(), *value);
+				next_bindings.push(next_binding);
+			}
+		}
+		bindings = next_bindings;
+	}
+	bindings
+}
+
+fn bind_template(
+	template: &PatternTemplate,
+	template_id: &str,
+	bindings: &BTreeMap<String, i64>,
+) -> Result<Option<Pattern>, String> {
+	let mut notes = Vec::new();
+	for note in &template.notes {
+		let x = bind_value(template, &note.x, bindings)?;
+		let y = bind_value(template, &note.y, bindings)?;
+		let cut_direction = bind_value(template, &note.cut_direction, bindings)?;
+		let note_type = bind_value(template, &note.note_type, bindings)?;
+		if !(0..4).contains(&x)
+			|| !(0..3).contains(&y)
+			|| !(0..9).contains(&cut_direction)
+			|| !is_note_type(note_type)
+		{
+			return Ok(None);
+		}
+		notes.push(PatternNote {
+			x,
+			y,
+			cut_direction,
+			note_type,
+			description: note.description.to_owned(),
+			beat_time_rel: note.beat_time_rel,
+			beat_time: note.beat_time,
+		});
+	}
+	let mut obstacles = Vec::new();
+	for wall in &template.obstacles {
+		let x = bind_value(template, &wall.x, bindings)?;
+		let y = bind_value(template, &wall.y, bindings)?;
+		if !(0..4).contains(&x) || !(0..3).contains(&y) {
+			return Ok(None);
+		}
+		obstacles.push(PatternWall {
+			x,
+			y,
+			duration: wall.duration,
+			padding: wall.padding,
+			width: wall.width,
+			wall_type: wall.wall_type,
+			description: wall.description.to_owned(),
+			wall_time_rel: wall.wall_time_rel,
+			wall_time: wall.wall_time,
+		});
+	}
+	let binding_names: Vec<String> = bindings
+		.iter()
+		.map(|(name, value)| format!("{}={}", name, value))
+		.collect();
+	let binding_label = binding_names.join(" ");
+	Ok(Some(Pattern {
+		id: format!("{} [{}]", template_id, binding_label),
+		template_id: Some(template_id.to_owned()),
+		source: PatternSource::Library,
+		description: format!("{} [{}]", template.description, binding_label),
+		notes,
+		obstacles,
+	}))
+}
+
+//the left saber, the right saber, a bomb, or negative for a note either saber can take
+fn is_note_type(note_type: i64) -> bool {
+	note_type <= 1 || note_type == NOTE_TYPE_BOMB
+}
+
+/*
+* The id of the template a pattern was expanded from, or the pattern's own id if it wasn't
+*/
+pub fn template_base_id(pattern: &Pattern) -> &str {
+	pattern.template_id.as_deref().unwrap_or(&pattern.id)
+}
+
+fn bind_value(
+	template: &PatternTemplate,
+	value: &TemplateValue,
+	bindings: &BTreeMap<String, i64>,
+) -> Result<i64, String> {
+	match value {
+		TemplateValue::Value(value) => Ok(*value),
+		TemplateValue::Expression(expression) => evaluate(expression, bindings).map_err(|error| {
+			format!(
+				"Could not evaluate \"{}\" in pattern template {}: {}",
+				expression, template.description, error
+			)
+		}),
+	}
+}
+
+fn evaluate(expression: &str, bindings: &BTreeMap<String, i64>) -> Result<i64, String> {
+	let expression = expression.trim();
+	if let Some(inner) = function_argument(expression, "opposite") {
+		return Ok(opposite_cut(evaluate(inner, bindings)?));
+	}
+	if let Some(inner) = function_argument(expression, "mirror") {
+		return Ok(mirror_cut(evaluate(inner, bindings)?));
+	}
+	if let Some(inner) = function_argument(expression, "rotate") {
+		let split = inner
+			.rfind(',')
+			.ok_or_else(|| "rotate needs a cut and a number of steps".to_owned())?;
+		let cut_direction = evaluate(&inner[..split], bindings)?;
+		let steps = evaluate(&inner[split + 1..], bindings)?;
+		return Ok(rotate_cut(cut_direction, steps));
+	}
+
+	//a sum of $variables and integers
+	let mut total = 0;
+	let mut term = String::new();
+	let mut sign = 1;
+	for character in expression.chars().chain(std::iter::once('+')) {
+		if (character == '+' || character == '-') && !term.trim().is_empty() {
+			total += sign * evaluate_term(term.trim(), bindings)?;
+			term.clear();
+			sign = if character == '-' { -1 } else { 1 };
+		} else if character == '-' {
+			sign = -sign;
+		} else if character != '+' {
+			term.push(character);
+		}
+	}
+	Ok(total)
+}
+
+fn evaluate_term(term: &str, bindings: &BTreeMap<String, i64>) -> Result<i64, String> {
+	if let Some(name) = term.strip_prefix('$') {
+		bindings
+			.get(name)
+			.cloned()
+			.ok_or_else(|| format!("unknown variable ${}", name))
+	} else {
+		term.parse::<i64>()
+			.map_err(|_| format!("\"{}\" is not a number or $variable", term))
+	}
+}
+
+fn function_argument<'a>(expression: &'a str, function: &str) -> Option<&'a str> {
+	expression
+		.strip_prefix(function)
+		.map(str::trim_start)
+		.and_then(|rest| rest.strip_prefix('('))
+		.and_then(|rest| rest.strip_suffix(')'))
+}
+
+pub fn mirror_cut(cut_direction: i64) -> i64 {
+	MIRRORED_CUTS
+		.get(cut_direction as usize)
+		.cloned()
+		.unwrap_or(cut_direction)
+}
+
+pub fn rotate_cut(cut_direction: i64, steps: i64) -> i64 {
+	match CLOCKWISE_CUTS.iter().position(|cut| *cut == cut_direction) {
+		Some(position) => {
+			let turns = CLOCKWISE_CUTS.len() as i64;
+			CLOCKWISE_CUTS[((position as i64 + steps).rem_euclid(turns)) as usize]
+		}
+		//dots have no direction to rotate
+		None => cut_direction,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn evaluate_with(expression: &str, bindings: &[(&str, i64)]) -> Result<i64, String> {
+		let bindings = bindings.iter().map(|(name, value)| (name.to_string(), *value)).collect();
+		evaluate(expression, &bindings)
+	}
+
+	fn template(template_json: &str) -> PatternTemplate {
+		serde_json::from_str(template_json).unwrap()
+	}
+
+	#[test]
+	fn sums_and_signs() {
+		assert_eq!(evaluate_with("$a+1", &[("a", 2)]), Ok(3));
+		assert_eq!(evaluate_with("-$a", &[("a", 2)]), Ok(-2));
+		assert_eq!(evaluate_with("$a--1", &[("a", 2)]), Ok(3));
+		assert_eq!(evaluate_with("3 - $a - $b", &[("a", 1), ("b", 1)]), Ok(1));
+	}
+
+	#[test]
+	fn cut_helpers() {
+		assert_eq!(evaluate_with("opposite($d)", &[("d", 0)]), Ok(1));
+		assert_eq!(evaluate_with("mirror($d)", &[("d", 2)]), Ok(3));
+		assert_eq!(evaluate_with("rotate($d, 2)", &[("d", 0)]), Ok(3));
+		//up turned to down, then one step clockwise
+		assert_eq!(evaluate_with("rotate(opposite($d), 1)", &[("d", 0)]), Ok(6));
+		assert_eq!(evaluate_with("rotate(8, 3)", &[]), Ok(8));
+	}
+
+	#[test]
+	fn unknown_variables_and_bad_terms() {
+		assert!(evaluate_with("$b+1", &[("a", 2)]).is_err());
+		assert!(evaluate_with("up", &[]).is_err());
+		assert!(evaluate_with("rotate($a)", &[("a", 0)]).is_err());
+	}
+
+	#[test]
+	fn unknown_variable_fails_the_template() {
+		let template = template(
+			r#"{ "description": "bad", "variables": { "a": [0] },
+			"notes": [{ "x": "$b", "y": 0, "cut_direction": 1, "note_type": 0, "description": "" }] }"#,
+		);
+		assert!(expand_template(&template, "bad").is_err());
+	}
+
+	#[test]
+	fn off_grid_bindings_are_left_out() {
+		let template = template(
+			r#"{ "description": "column", "variables": { "base": [0, 1, 2, 3], "hand": [0, 1, 2] },
+			"notes": [{ "x": "$base+1", "y": 0, "cut_direction": 1, "note_type": "$hand", "description": "" }],
+			"obstacles": [{ "x": 0, "y": "$base", "duration": 1.0, "padding": 0.0, "width": 1.0, "wall_type": 0, "description": "" }] }"#,
+		);
+		let patterns = expand_template(&template, "column").unwrap();
+		//x runs off the grid for base 3, the wall's layer for base 3, and hand 2 isn't a note type
+		assert_eq!(patterns.len(), 6);
+		assert!(patterns.iter().all(|pattern| pattern.notes[0].x < 4 && pattern.notes[0].note_type < 2));
+		assert!(patterns.iter().all(|pattern| template_base_id(pattern) == "column"));
+	}
+}
@@ -0,0 +1,16 @@
+use crate::PatternNote;
+
+/*
+* A note timed in beats from the start of the map, like the processed notes of a generated map
+*/
+pub fn note(x: i64, y: i64, cut_direction: i64, note_type: i64, beat_time: f64) -> PatternNote {
+	PatternNote {
+		x,
+		y,
+		cut_direction,
+		note_type,
+		description: String::new(),
+		beat_time_rel: 0,
+		beat_time: Some(beat_time),
+	}
+}
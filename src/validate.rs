@@ -0,0 +1,148 @@
+use crate::bombs::{bomb_is_safe, map_note_times, NOTE_TYPE_BOMB};
+use crate::flow::{note_order, SAME_TIME_TOLERANCE};
+use crate::parity::find_parity_breaks;
+use crate::hands::find_hand_issues;
+use crate::settings::GeneratorSettings;
+use crate::vision::find_vision_blocks;
+use crate::PatternNote;
+use std::collections::BTreeMap;
+
+pub struct ValidationIssue {
+	pub rule: &'static str,
+	pub time: f64,
+	pub description: String,
+}
+
+/*
+* Note counts and validation results for a generated map, used to compare one run with the next
+*/
+#[derive(Default)]
+pub struct MapSummary {
+	pub left_notes: usize,
+	pub right_notes: usize,
+	pub bombs: usize,
+	//number of issues found by each rule
+	pub issues: BTreeMap<&'static str, usize>,
+}
+
+/*
+* Check the notes of a generated map, they should carry their beat_time from the start of the map
+*/
+pub fn validate_notes(
+	notes: &[PatternNote],
+	settings: &GeneratorSettings,
+	vision_window: f64,
+) -> Vec<ValidationIssue> {
+	let mut sorted_notes: Vec<&PatternNote> = notes.iter().collect();
+	sorted_notes.sort_by(|a, b| note_order(a).partial_cmp(&note_order(b)).unwrap());
+	let swinging_notes: Vec<PatternNote> = notes
+		.iter()
+		.filter(|note| note.note_type != NOTE_TYPE_BOMB)
+		.cloned()
+		.collect();
+	let swinging_times = map_note_times(&swinging_notes);
+
+	let mut issues = Vec::new();
+	for (note_id, note) in sorted_notes.iter().enumerate() {
+		let time = note_order(note);
+		if !(0..4).contains(&note.x) || !(0..3).contains(&note.y) {
+			issues.push(ValidationIssue {
+				rule: "off grid",
+				time,
+				description: format!("note at {},{} is off the grid", note.x, note.y),
+			});
+		}
+		if note.note_type == NOTE_TYPE_BOMB && !bomb_is_safe(note, time, &swinging_times) {
+			issues.push(ValidationIssue {
+				rule: "bomb in swing path",
+				time,
+				description: format!("bomb at {},{} is in a swing path", note.x, note.y),
+			});
+		}
+		for other in sorted_notes[note_id + 1..].iter() {
+			if note_order(other) - time > SAME_TIME_TOLERANCE {
+				break;
+			}
+			if other.x == note.x && other.y == note.y {
+				issues.push(ValidationIssue {
+					rule: "overlap",
+					time,
+					description: format!("two notes at {},{}", note.x, note.y),
+				});
+			} else if other.note_type == note.note_type
+				&& note.note_type != NOTE_TYPE_BOMB
+				&& other.cut_direction != note.cut_direction
+			{
+				issues.push(ValidationIssue {
+					rule: "split swing",
+					time,
+					description: format!(
+						"one hand has notes at {},{} and {},{} cut different ways",
+						note.x, note.y, other.x, other.y
+					),
+				});
+			}
+		}
+	}
+	for note in find_parity_breaks(notes, Some(&settings.parity)) {
+		issues.push(ValidationIssue {
+			rule: "parity",
+			time: note_order(note),
+			description: format!(
+				"note at {},{} swings the same way as the last note on that hand",
+				note.x, note.y
+			),
+		});
+	}
+	for (blocker, hidden) in find_vision_blocks(notes, vision_window) {
+		issues.push(ValidationIssue {
+			rule: "vision block",
+			time: note_order(hidden),
+			description: format!(
+				"note at {},{} is hidden by the note at {},{}",
+				hidden.x, hidden.y, blocker.x, blocker.y
+			),
+		});
+	}
+	for hand_issue in find_hand_issues(notes, settings.crossovers_allowed()) {
+		issues.push(ValidationIssue {
+			rule: hand_issue.rule,
+			time: note_order(hand_issue.note),
+			description: format!(
+				"{} note at {},{} against the {} note at {},{}",
+				hand_name(hand_issue.note.note_type),
+				hand_issue.note.x,
+				hand_issue.note.y,
+				hand_name(hand_issue.other.note_type),
+				hand_issue.other.x,
+				hand_issue.other.y
+			),
+		});
+	}
+	issues.sort_by(|a, b| a.time.partial_cmp(&b.time).unwrap());
+	issues
+}
+
+fn hand_name(note_type: i64) -> &'static str {
+	if note_type == 0 {
+		"left"
+	} else {
+		"right"
+	}
+}
+
+pub fn summarize_notes(notes: &[PatternNote], issues: &[ValidationIssue]) -> MapSummary {
+	let mut summary = MapSummary::default();
+	for note in notes {
+		match note.note_type {
+			0 => summary.left_notes += 1,
+			1 => summary.right_notes += 1,
+			NOTE_TYPE_BOMB => summary.bombs += 1,
+			_ => {}
+		}
+	}
+	for issue in issues {
+		*summary.issues.entry(issue.rule).or_insert(0) += 1;
+	}
+	summary
+}
@@ -0,0 +1,93 @@
+use crate::flow::{note_order, SAME_TIME_TOLERANCE};
+use crate::PatternNote;
+
+//how much of the track (in meters) a note in the middle of the grid hides behind it
+const VISION_BLOCK_DISTANCE: f64 = 5.0;
+//where a blocking note is moved to, bottom layer first
+const UNBLOCKED_LAYERS: [i64; 2] = [0, 2];
+
+pub enum VisionFix {
+	Keep,
+	MoveTo(i64),
+	Drop,
+	//the note is behind one that's already in the map
+	Hidden,
+}
+
+/*
+* A note hides everything behind it for VISION_BLOCK_DISTANCE, the notes come towards the player at
+* the note jump speed (in meters per second) so that's how long, in beats, a note after it can't be seen
+*/
+pub fn vision_window_beats(note_jump_speed: f64, beats_per_minute: f64) -> f64 {
+	(VISION_BLOCK_DISTANCE / note_jump_speed) * (beats_per_minute / 60.0)
+}
+
+//the center two columns of the middle layer, right in front of the player's eyes
+pub fn is_vision_blocker(note: &PatternNote) -> bool {
+	(1..=2).contains(&note.x) && note.y == 1
+}
+
+/*
+* A note hides one that comes after it inside the window, in its own column or the ones either side
+*/
+pub fn hides(blocker: &PatternNote, note: &PatternNote, window_beats: f64) -> bool {
+	let gap = note_order(note) - note_order(blocker);
+	is_vision_blocker(blocker)
+		&& gap > SAME_TIME_TOLERANCE
+		&& gap < window_beats
+		&& (note.x - blocker.x).abs() <= 1
+}
+
+/*
+* Every pair of notes where the first hides the second, notes should carry their beat_time
+*/
+pub fn find_vision_blocks(notes: &[PatternNote], window_beats: f64) -> Vec<(&PatternNote, &PatternNote)> {
+	let mut sorted_notes: Vec<&PatternNote> = notes.iter().collect();
+	sorted_notes.sort_by(|a, b| note_order(a).partial_cmp(&note_order(b)).unwrap());
+
+	let mut blocks = Vec::new();
+	for (note_id, blocker) in sorted_notes.iter().enumerate() {
+		if !is_vision_blocker(blocker) {
+			continue;
+		}
+		for note in sorted_notes[note_id + 1..].iter() {
+			if note_order(note) - note_order(blocker) >= window_beats {
+				break;
+			}
+			if hides(blocker, note, window_beats) {
+				blocks.push((*blocker, *note));
+			}
+		}
+	}
+	blocks
+}
+
+/*
+* What to do with a note that hides others in the same pattern: move it to the bottom or top layer
+* of its column if nothing else is there at the time, otherwise drop it.
+* recent_notes are the notes already in the map inside the window before the pattern, they can't be
+* moved so a note one of them hides is dropped.
+*/
+pub fn fix_vision_block(
+	note: &PatternNote,
+	pattern_notes: &[PatternNote],
+	recent_notes: &[&PatternNote],
+	window_beats: f64,
+) -> VisionFix {
+	if recent_notes.iter().any(|recent| hides(recent, note, window_beats)) {
+		return VisionFix::Hidden;
+	}
+	if !pattern_notes.iter().any(|other| hides(note, other, window_beats)) {
+		return VisionFix::Keep;
+	}
+	let time = note_order(note);
+	for layer in UNBLOCKED_LAYERS.iter() {
+		let layer_is_free = !pattern_notes.iter().chain(recent_notes.iter().cloned()).any(|other| {
+			(note_order(other) - time).abs() <= SAME_TIME_TOLERANCE && other.x == note.x && other.y == *layer
+		});
+		if layer_is_free {
+			return VisionFix::MoveTo(*layer);
+		}
+	}
+	VisionFix::Drop
+}
@@ -0,0 +1,177 @@
+use crate::report::GenerationStats;
+use crate::settings::get_generator_settings;
+use crate::validate::{summarize_notes, validate_notes, MapSummary, ValidationIssue};
+use crate::{create_bsaber_map, get_seed, get_vision_window_beats, write_song_zip};
+use glob::glob;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+//how many validation issues are listed after each run, the rest are only counted
+const MAX_LISTED_ISSUES: usize = 5;
+//files the map is built from, outside of the pattern library
+const WATCHED_FILES: [&str; 4] = [
+	"src/song/info.dat",
+	"src/song/peak_times.txt",
+	"src/song/generator.json",
+	"src/song/library.json",
+];
+
+/*
+* cargo run -- watch
+* cargo run -- watch --seed 1234
+* Regenerate the map and the zip every time a pattern in src/patterns, info.dat, the peak times,
+* the settings or library.json change. Library roots outside src/patterns and the transition model
+* are read again on every run but editing them doesn't start one.
+* Every run uses the same seed, so the only changes are the ones the edits make.
+* A run that fails on a bad input file prints why and the watch carries on.
+*/
+pub fn run(args: &[String]) -> std::io::Result<()> {
+	let seed = get_seed(args);
+	let mut snapshot = watched_files();
+	let mut summary = report_regenerate(regenerate(seed), None);
+	println!("Watching for changes, ctrl+c to stop");
+	loop {
+		thread::sleep(POLL_INTERVAL);
+		let next_snapshot = watched_files();
+		if next_snapshot == snapshot {
+			continue;
+		}
+		for changed_file in changed_files(&snapshot, &next_snapshot) {
+			println!("Changed: {}", changed_file.display());
+		}
+		snapshot = next_snapshot;
+		let next_summary = report_regenerate(regenerate(seed), summary.as_ref());
+		if next_summary.is_some() {
+			summary = next_summary;
+		}
+	}
+}
+
+/*
+* Print how a run went, against the last run that worked if there was one
+*/
+fn report_regenerate(
+	result: Result<MapSummary, String>,
+	previous: Option<&MapSummary>,
+) -> Option<MapSummary> {
+	match (result, previous) {
+		(Ok(summary), Some(previous)) => {
+			print_summary_diff(previous, &summary);
+			Some(summary)
+		}
+		(Ok(summary), None) => {
+			print_summary(&summary);
+			Some(summary)
+		}
+		(Err(error), _) => {
+			println!("Could not regenerate the map: {}", error);
+			None
+		}
+	}
+}
+
+fn regenerate(seed: u64) -> Result<MapSummary, String> {
+	let mut processed_notes = Vec::new();
+	let mut stats = GenerationStats::default();
+	let map_string = create_bsaber_map(&mut processed_notes, &mut stats, seed)?;
+	write_song_zip(map_string).map_err(|error| format!("Could not write the song zip: {}", error))?;
+	let issues = validate_notes(
+		&processed_notes,
+		&get_generator_settings()?,
+		get_vision_window_beats()?,
+	);
+	for issue in issues.iter().take(MAX_LISTED_ISSUES) {
+		print_issue(issue);
+	}
+	if issues.len() > MAX_LISTED_ISSUES {
+		println!("  ... and {} more", issues.len() - MAX_LISTED_ISSUES);
+	}
+	Ok(summarize_notes(&processed_notes, &issues))
+}
+
+fn print_issue(issue: &ValidationIssue) {
+	println!("  beat {:.2} {}: {}", issue.time, issue.rule, issue.description);
+}
+
+//modified time of every watched file that exists
+fn watched_files() -> BTreeMap<PathBuf, SystemTime> {
+	let mut files = BTreeMap::new();
+	let pattern_files = glob("src/patterns/**/*.json")
+		.expect("Failed to read glob pattern")
+		.filter_map(Result::ok);
+	let other_files = WATCHED_FILES.iter().map(PathBuf::from);
+	for path in pattern_files.chain(other_files) {
+		if let Ok(modified) = fs::metadata(&path).and_then(|metadata| metadata.modified()) {
+			files.insert(path, modified);
+		}
+	}
+	files
+}
+
+fn changed_files(
+	before: &BTreeMap<PathBuf, SystemTime>,
+	after: &BTreeMap<PathBuf, SystemTime>,
+) -> Vec<PathBuf> {
+	let mut changed: Vec<PathBuf> = after
+		.iter()
+		.filter(|(path, modified)| before.get(*path) != Some(modified))
+		.map(|(path, _)| path.to_owned())
+		.collect();
+	changed.extend(
+		before
+			.keys()
+			.filter(|path| !after.contains_key(*path))
+			.cloned(),
+	);
+	changed
+}
+
+fn print_summary(summary: &MapSummary) {
+	println!(
+		"notes: {} left, {} right, {} bombs",
+		summary.left_notes, summary.right_notes, summary.bombs
+	);
+	if summary.issues.is_empty() {
+		println!("validation: no issues");
+	}
+	for (rule, count) in &summary.issues {
+		println!("validation: {} {}", count, rule);
+	}
+}
+
+fn print_summary_diff(before: &MapSummary, after: &MapSummary) {
+	println!(
+		"notes: left {}, right {}, bombs {}",
+		count_diff(before.left_notes, after.left_notes),
+		count_diff(before.right_notes, after.right_notes),
+		count_diff(before.bombs, after.bombs)
+	);
+	let mut rules: Vec<&&str> = before.issues.keys().chain(after.issues.keys()).collect();
+	rules.sort();
+	rules.dedup();
+	if rules.is_empty() {
+		println!("validation: no issues");
+	}
+	for rule in rules {
+		println!(
+			"validation: {} {}",
+			rule,
+			count_diff(
+				before.issues.get(*rule).cloned().unwrap_or(0),
+				after.issues.get(*rule).cloned().unwrap_or(0)
+			)
+		);
+	}
+}
+
+fn count_diff(before: usize, after: usize) -> String {
+	if before == after {
+		format!("{}", after)
+	} else {
+		format!("{} -> {} ({:+})", before, after, after as i64 - before as i64)
+	}
+}